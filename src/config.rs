@@ -0,0 +1,923 @@
+//! Command-line configuration and the parsers for its values.
+
+use crate::pattern::{parse_pattern, Pattern};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+/// Struct representing command-line arguments parsed using `clap`.
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// Run a utility instead of the server.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The target host to which the incoming requests will be forwarded; `{listen_port}` is replaced with the listen port.
+    #[arg(short = 'H', long, default_value = "127.0.0.1")]
+    pub target_host: String,
+
+    /// The port on the target host to which the incoming requests will be forwarded.
+    #[arg(short = 'p', long, default_value = "8080")]
+    pub target_port: u16,
+
+    /// Derive the target port from the listen port plus this offset, instead of using `--target-port`.
+    #[arg(long, allow_negative_numbers = true, conflicts_with = "target_port")]
+    pub target_port_offset: Option<i32>,
+
+    /// The port on which the server will listen for incoming connections.
+    #[arg(short = 'm', long, default_value = "8888")]
+    pub listen_port: u16,
+
+    /// The local address to listen on.
+    #[arg(long, value_name = "IP", default_value = "0.0.0.0")]
+    pub listen_addr: IpAddr,
+
+    /// Listen on this address instead if `--listen-addr` cannot be bound, e.g. while its interface is still coming up.
+    /// The primary address is retried in the background and takes over once it binds.
+    #[arg(long, value_name = "IP")]
+    pub backup_listen_addr: Option<IpAddr>,
+
+    /// How often to retry binding `--listen-addr` while on the backup address, in seconds.
+    #[arg(long, value_name = "SECONDS", default_value = "10", requires = "backup_listen_addr", value_parser = clap::value_parser!(u64).range(1..))]
+    pub primary_retry_interval: u64,
+
+    /// What to do with client data once the payload has been sent.
+    #[arg(long, value_enum, default_value = "proxy")]
+    pub mode: Mode,
+
+    /// The number of packets to skip before starting to forward data to the target server.
+    #[arg(short, long, default_value = "0")]
+    pub skip: usize,
+
+    /// The number of bytes to drop after the skipped packets, however the client's reads are split.
+    #[arg(long, default_value = "0")]
+    pub skip_bytes: usize,
+
+    /// Close the connection if the packets dropped by `--skip` add up to more than this many bytes (0 means unlimited).
+    #[arg(long, default_value = "0")]
+    pub max_handshake_bytes: usize,
+
+    /// Close the connection if nothing has been forwarded this many seconds after accept (covers the skip phase and the dial).
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub handshake_timeout: Option<u64>,
+
+    /// Require clients to send this token and a newline before anything else; the line is not forwarded.
+    #[arg(long)]
+    pub preauth_token: Option<String>,
+
+    /// Record the bytes forwarded in each direction of every session to files in this directory.
+    #[arg(long, value_name = "DIR")]
+    pub record_dir: Option<PathBuf>,
+
+    /// Only record this share of sessions (e.g. `1%`) and log detailed timing for them, leaving the rest on the fast path.
+    #[arg(long, value_name = "PERCENT", requires = "record_dir", value_parser = parse_percent)]
+    pub inspect_sample: Option<f64>,
+
+    /// The fake response sent to every client before forwarding starts [default: ws-101, or none in ftp mode].
+    #[arg(long, value_enum)]
+    pub payload_preset: Option<PayloadPreset>,
+
+    /// The response to send with `--payload-preset custom`; supports `\r`, `\n`, `\t`, `\\` and `\xNN` escapes.
+    #[arg(long, required_if_eq("payload_preset", "custom"))]
+    pub payload: Option<String>,
+
+    /// Only send the payload when the client's first packet starts with one of these prefixes (may be repeated).
+    /// Clients that don't match get no payload and have all of their data forwarded.
+    #[arg(long = "inject-if-prefix", value_name = "PREFIX")]
+    pub inject_if_prefix: Vec<String>,
+
+    /// Only dial the target for clients whose first packet matches this pattern; others get `--reject-payload` and are closed.
+    ///
+    /// A pattern is a small subset of regular expressions over bytes: literals, `.` for any byte,
+    /// `[a-z]` and `[^\r\n]` classes, `\d`, `\w`, `\s` and their negations `\D`, `\W`, `\S`, the
+    /// `*`, `+` and `?` repetitions of a single item, and `^` and `$` to anchor the start and end.
+    /// Escapes are those of `--payload` (`\r`, `\n`, `\t`, `\xNN`), and a backslash makes any other
+    /// character literal. There is no alternation (`|`), grouping (`(...)`) or counted repetition (`{3}`).
+    #[arg(long, value_name = "PATTERN", value_parser = parse_pattern)]
+    pub expect_pattern: Option<Pattern>,
+
+    /// The bytes sent to clients that fail `--expect-pattern` before they are closed; supports the same escapes as `--payload`.
+    #[arg(long, requires = "expect_pattern")]
+    pub reject_payload: Option<String>,
+
+    /// The resolved reject bytes, filled in from `reject_payload` after parsing.
+    #[arg(skip)]
+    pub reject_bytes: Vec<u8>,
+
+    /// Further exchanges to run with the client after the payload, in order (may be repeated).
+    /// `send:BYTES` writes to the client; `expect:BYTES` waits until the client has sent `BYTES` and drops everything up to it.
+    /// Both take the same escapes as `--payload`. Data after the last match is forwarded as usual.
+    #[arg(long = "payload-stage", value_name = "STAGE", value_parser = parse_payload_stage)]
+    pub payload_stages: Vec<PayloadStage>,
+
+    /// The resolved payload bytes, filled in from `payload_preset` and `payload` after parsing.
+    #[arg(skip)]
+    pub payload_bytes: Vec<u8>,
+
+    /// Send `--client-keepalive-payload` to the client after this many seconds without data towards it.
+    #[arg(long, value_name = "SECONDS", requires = "client_keepalive_payload", value_parser = clap::value_parser!(u64).range(1..))]
+    pub client_keepalive_interval: Option<u64>,
+
+    /// The no-op bytes sent as a client keepalive, e.g. an unmasked WebSocket ping (`\x89\x00`); supports the same escapes as `--payload`.
+    /// They are injected into the stream, so they must be something the client's protocol ignores.
+    #[arg(long, requires = "client_keepalive_interval")]
+    pub client_keepalive_payload: Option<String>,
+
+    /// The resolved client keepalive bytes, filled in from `client_keepalive_payload` after parsing.
+    #[arg(skip)]
+    pub client_keepalive_bytes: Vec<u8>,
+
+    /// The number of lines to strip from the start of the target server's response.
+    #[arg(long, default_value = "0", conflicts_with = "strip_response_bytes")]
+    pub strip_response_lines: usize,
+
+    /// The number of bytes to strip from the start of the target server's response.
+    #[arg(long, default_value = "0")]
+    pub strip_response_bytes: usize,
+
+    /// The size in bytes of the buffer used to read from the client (the uplink).
+    #[arg(long, default_value = "4096", value_parser = clap::value_parser!(u32).range(1..))]
+    pub client_buffer_size: u32,
+
+    /// The size in bytes of the buffer used to read from the target server (the downlink).
+    #[arg(long, default_value = "4096", value_parser = clap::value_parser!(u32).range(1..))]
+    pub server_buffer_size: u32,
+
+    /// Batch small reads and forward them with vectored writes instead of one write per read.
+    #[arg(long)]
+    pub coalesce: bool,
+
+    /// With `--coalesce`, flush as soon as this many bytes are buffered.
+    #[arg(long, default_value = "16384")]
+    pub coalesce_bytes: usize,
+
+    /// With `--coalesce`, flush buffered data at most this many milliseconds after it was read.
+    #[arg(long, default_value = "5")]
+    pub coalesce_interval_ms: u64,
+
+    /// Split the client's TLS ClientHello into TCP segments of this many bytes on the way to the target (0 disables).
+    #[arg(long, default_value = "0")]
+    pub hello_fragment_size: usize,
+
+    /// Wait this many milliseconds between ClientHello fragments.
+    #[arg(long, default_value = "0", requires = "hello_fragment_size")]
+    pub hello_fragment_delay_ms: u64,
+
+    /// Wrap each ClientHello fragment in its own TLS record instead of splitting one record across segments.
+    #[arg(long, requires = "hello_fragment_size")]
+    pub hello_fragment_records: bool,
+
+    /// Additional addresses that reach this proxy (e.g. a public address forwarded to it), refused as targets.
+    #[arg(long = "self-address", value_name = "ADDR:PORT")]
+    pub self_addresses: Vec<SocketAddr>,
+
+    /// The DSCP value (0-63) to mark forwarded traffic with on both the client and upstream sockets.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=63))]
+    pub dscp: Option<u8>,
+
+    /// Clamp the TCP maximum segment size on the listener and upstream sockets, to avoid PMTUD black holes.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u16).range(88..))]
+    pub mss_clamp: Option<u16>,
+
+    /// Follow HTTP/2 (prior knowledge) traffic frame by frame and log per-connection stream and reset counts.
+    #[arg(long)]
+    pub h2_metrics: bool,
+
+    /// Close a connection once this many bytes have been forwarded in both directions together (0 means unlimited).
+    #[arg(long, default_value = "0")]
+    pub max_bytes_per_conn: u64,
+
+    /// Only accept connections during this local time window, e.g. `08:00-22:00` (may be repeated).
+    /// A window whose end is before its start wraps around midnight.
+    #[arg(long = "allow-hours", value_name = "HH:MM-HH:MM", value_parser = parse_time_window)]
+    pub allow_hours: Vec<TimeWindow>,
+
+    /// Export an IPFIX flow record for each direction of every finished connection to this UDP collector.
+    #[arg(long, value_name = "ADDR:PORT")]
+    pub flow_collector: Option<SocketAddr>,
+
+    /// The IPFIX observation domain ID put in exported flow messages.
+    #[arg(long, default_value = "0")]
+    pub flow_domain_id: u32,
+
+    /// The maximum number of client connections handled at once (0 means unlimited).
+    #[arg(long, default_value = "0")]
+    pub max_connections: u64,
+
+    /// With `--max-connections`, keep this many of the slots for clients matching `--reserve-allow`.
+    #[arg(long, default_value = "0", requires = "max_connections")]
+    pub reserved_connections: u64,
+
+    /// A client address or CIDR range allowed to use the reserved connection slots (may be repeated).
+    #[arg(long = "reserve-allow", value_name = "CIDR", value_parser = parse_cidr)]
+    pub reserve_allow: Vec<Cidr>,
+
+    /// The maximum number of connections handled at once from a single client IP (0 means unlimited).
+    #[arg(long, default_value = "0")]
+    pub max_connections_per_ip: u64,
+
+    /// Bytes sent to clients refused by `--max-connections` or `--max-connections-per-ip` before they are closed,
+    /// e.g. `HTTP/1.1 503 Service Unavailable\r\nRetry-After: 5\r\n\r\n`; supports the same escapes as `--payload`.
+    #[arg(long, value_name = "STRING")]
+    pub busy_response: Option<String>,
+
+    /// The resolved busy response bytes, filled in from `busy_response` after parsing.
+    #[arg(skip)]
+    pub busy_response_bytes: Vec<u8>,
+
+    /// Only serve client addresses that first connected to these ports in this order (repeat the flag for each knock).
+    /// The knock ports accept and close connections at once; each knock must follow the previous one within 10 seconds.
+    #[arg(long = "knock", value_name = "PORT")]
+    pub knock_ports: Vec<u16>,
+
+    /// How long an address that completed the knock sequence may open new connections, in seconds.
+    #[arg(long, value_name = "SECONDS", default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    pub knock_window: u64,
+
+    /// Send a PROXY protocol v1 header with the client's address to the target before any data.
+    #[arg(long)]
+    pub proxy_protocol: bool,
+
+    /// Advertise the listener on the local network via mDNS as a `_proxy._tcp` service.
+    #[arg(long)]
+    pub mdns: bool,
+
+    /// The mDNS service instance name.
+    #[arg(long, default_value = "proxy-stream")]
+    pub mdns_name: String,
+
+    /// A `key=value` entry for the mDNS TXT record (may be repeated); `version` and `mode` are always included.
+    #[arg(long = "mdns-txt", value_name = "KEY=VALUE")]
+    pub mdns_txt: Vec<String>,
+
+    /// Ask the router for a port mapping to the listener with NAT-PMP, renewing it while the server runs.
+    #[arg(long)]
+    pub nat_pmp: bool,
+
+    /// The NAT-PMP gateway to ask (defaults to the IPv4 default gateway).
+    #[arg(long, value_name = "IP", requires = "nat_pmp")]
+    pub nat_pmp_gateway: Option<std::net::Ipv4Addr>,
+
+    /// Ask the router for a port mapping to the listener with UPnP IGD, renewing it while the server runs.
+    #[arg(long, conflicts_with = "nat_pmp")]
+    pub upnp: bool,
+
+    /// Query this STUN server at startup and log the proxy's public IP address.
+    #[arg(long, value_name = "HOST:PORT")]
+    pub stun_server: Option<String>,
+
+    /// The maximum number of concurrent connect attempts to a single target (0 means unlimited).
+    #[arg(long, default_value = "0")]
+    pub max_upstream_dials: usize,
+
+    /// Reach the target through this SOCKS5 server (`IP:PORT`, no authentication).
+    /// The target's host name is sent to the SOCKS server to resolve (`socks5h`), so it is never looked up locally.
+    #[arg(long, value_name = "IP:PORT", conflicts_with_all = ["dns_fallback", "dns_ttl_override"])]
+    pub upstream_socks5: Option<SocketAddr>,
+
+    /// A DNS server (`IP` or `IP:PORT`) to ask when the system resolver fails or times out (may be repeated, tried in order).
+    #[arg(long = "dns-fallback", value_name = "ADDR", value_parser = parse_dns_server)]
+    pub dns_fallback: Vec<SocketAddr>,
+
+    /// Reuse the target's resolved addresses for this many seconds instead of resolving for every connection.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub dns_ttl_override: Option<u64>,
+
+    /// A shell command to run in the background when a connection to the target is established.
+    /// It gets `PROXY_STREAM_CLIENT_IP`, `PROXY_STREAM_CLIENT_PORT`, `PROXY_STREAM_TARGET` and `PROXY_STREAM_TARGET_ADDR` in its environment.
+    #[arg(long, value_name = "COMMAND")]
+    pub on_connect: Option<String>,
+
+    /// A shell command to run in the background when such a connection closes.
+    /// It gets the `--on-connect` variables plus `PROXY_STREAM_BYTES_UP`, `PROXY_STREAM_BYTES_DOWN` and `PROXY_STREAM_REASON`.
+    #[arg(long, value_name = "COMMAND")]
+    pub on_close: Option<String>,
+
+    /// After dialing, wait for the target to send data matching this pattern (see `--expect-pattern` for the syntax) before forwarding, e.g. `^SSH-2\.0-`.
+    /// On a mismatch the connection is closed and the address is tried last for `--banner-penalty` seconds.
+    #[arg(long, value_name = "PATTERN", value_parser = parse_pattern)]
+    pub expect_banner: Option<Pattern>,
+
+    /// How long to wait for the target's banner, in seconds.
+    #[arg(long, value_name = "SECONDS", default_value = "5", requires = "expect_banner", value_parser = clap::value_parser!(u64).range(1..))]
+    pub banner_timeout: u64,
+
+    /// How long a target address that sent a wrong banner is tried after the others, in seconds.
+    #[arg(long, value_name = "SECONDS", default_value = "60", requires = "expect_banner")]
+    pub banner_penalty: u64,
+
+    /// How long to wait for each resolver, in seconds.
+    #[arg(long, default_value = "5", value_parser = clap::value_parser!(u64).range(1..))]
+    pub dns_timeout: u64,
+
+    /// Write every log line as a single JSON object with stable field names instead of plain text.
+    #[arg(long)]
+    pub log_json: bool,
+
+    /// Where log lines are written.
+    #[arg(long, value_enum, default_value = "stdout")]
+    pub log_target: LogTarget,
+
+    /// The file to append log lines to with `--log-target file`.
+    #[arg(long, required_if_eq("log_target", "file"))]
+    pub log_file: Option<PathBuf>,
+
+    /// The syslog facility used with `--log-target syslog`.
+    #[arg(long, value_enum, default_value = "daemon")]
+    pub syslog_facility: SyslogFacility,
+
+    /// The identifier log lines are tagged with in syslog and journald.
+    #[arg(long, default_value = "proxy-stream")]
+    pub log_tag: String,
+
+    /// The number of worker threads for the multi-threaded runtime (defaults to the number of CPU cores).
+    #[arg(long, conflicts_with = "current_thread")]
+    pub worker_threads: Option<NonZeroUsize>,
+
+    /// The maximum number of threads the runtime may spawn for blocking operations.
+    #[arg(long)]
+    pub max_blocking_threads: Option<NonZeroUsize>,
+
+    /// Run everything on a single thread, for constrained devices.
+    #[arg(long)]
+    pub current_thread: bool,
+
+    /// Accept `ctl` requests on a Unix socket at this path.
+    #[arg(long)]
+    pub control_socket: Option<PathBuf>,
+
+    /// On SIGUSR1, also write the stats snapshot to this file as JSON.
+    #[arg(long)]
+    pub stats_file: Option<PathBuf>,
+
+    /// The soft open file limit to request at startup (defaults to the hard limit).
+    #[arg(long)]
+    pub nofile_limit: Option<u64>,
+}
+
+/// Utilities run instead of the server.
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Check whether a payload and/or TLS SNI gets through to a host, e.g. via an ISP's bug host.
+    Probe(ProbeArgs),
+    /// Work with payload files.
+    Payload(PayloadArgs),
+    /// Query or control a running server through its `--control-socket`.
+    Ctl(CtlArgs),
+}
+
+/// Arguments for the `ctl` subcommand.
+#[derive(clap::Args, Debug, Clone)]
+pub struct CtlArgs {
+    /// The server's control socket.
+    #[arg(long)]
+    pub socket: PathBuf,
+
+    #[command(subcommand)]
+    pub action: CtlAction,
+}
+
+/// A request to the running server.
+#[derive(Subcommand, Debug, Clone)]
+pub enum CtlAction {
+    /// Show the server's counters.
+    Status,
+    /// List the live connections and their ids.
+    Connections,
+    /// Close the connection with this id.
+    Kill { id: u64 },
+    /// Stop sending new connections to a target address; existing ones carry on.
+    Drain { addr: SocketAddr },
+    /// Send new connections to a drained target address again.
+    Undrain { addr: SocketAddr },
+}
+
+/// Arguments for the `probe` subcommand.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ProbeArgs {
+    /// The host and port to connect to.
+    #[arg(value_name = "HOST:PORT")]
+    pub address: String,
+
+    /// A request to send first, with `\r`, `\n`, `\t`, `\\` and `\xNN` escapes; the reply's first line is reported.
+    #[arg(long)]
+    pub payload: Option<String>,
+
+    /// Send a TLS ClientHello with this server name and report whether a ServerHello comes back.
+    #[arg(long)]
+    pub sni: Option<String>,
+
+    /// How long to wait for the connection and for each reply, in seconds.
+    #[arg(long, default_value = "10")]
+    pub timeout: u64,
+}
+
+/// Arguments for the `payload` subcommand.
+#[derive(clap::Args, Debug, Clone)]
+pub struct PayloadArgs {
+    #[command(subcommand)]
+    pub command: PayloadCommand,
+}
+
+/// What to do with a payload file.
+#[derive(Subcommand, Debug, Clone)]
+pub enum PayloadCommand {
+    /// Check a payload written for `--payload` and print the exact bytes it sends.
+    Lint(LintArgs),
+}
+
+/// Arguments for `payload lint`.
+#[derive(clap::Args, Debug, Clone)]
+pub struct LintArgs {
+    /// The file holding the payload, as it would be passed with `--payload "$(cat FILE)"`.
+    pub file: PathBuf,
+}
+
+/// How client connections are served.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Forward client data to the target server.
+    Proxy,
+    /// Echo client data back without dialing the target, for testing payload and skip settings.
+    Echo,
+    /// Forward to an FTP server, relaying the data connections of passive-mode transfers.
+    Ftp,
+}
+
+/// Rejects option combinations that clap accepts but that cannot work together.
+///
+/// Each error names the offending option and why it does not apply, so a setting is never
+/// silently ignored at runtime.
+pub fn validate(args: &Args) -> Result<(), String> {
+    // Echo mode never dials the target, so nothing aimed at the target or its replies can take effect.
+    if args.mode == Mode::Echo {
+        let target_only: [(&str, bool); 22] = [
+            ("--target-port-offset", args.target_port_offset.is_some()),
+            ("--self-address", !args.self_addresses.is_empty()),
+            ("--proxy-protocol", args.proxy_protocol),
+            ("--hello-fragment-size", args.hello_fragment_size > 0),
+            ("--hello-fragment-delay-ms", args.hello_fragment_delay_ms > 0),
+            ("--coalesce", args.coalesce),
+            ("--dscp", args.dscp.is_some()),
+            ("--max-bytes-per-conn", args.max_bytes_per_conn > 0),
+            ("--record-dir", args.record_dir.is_some()),
+            ("--inspect-sample", args.inspect_sample.is_some()),
+            ("--dns-fallback", !args.dns_fallback.is_empty()),
+            ("--dns-ttl-override", args.dns_ttl_override.is_some()),
+            ("--upstream-socks5", args.upstream_socks5.is_some()),
+            ("--expect-banner", args.expect_banner.is_some()),
+            ("--on-connect", args.on_connect.is_some()),
+            ("--on-close", args.on_close.is_some()),
+            ("--max-upstream-dials", args.max_upstream_dials > 0),
+            ("--strip-response-lines", args.strip_response_lines > 0),
+            ("--strip-response-bytes", args.strip_response_bytes > 0),
+            ("--client-keepalive-interval", args.client_keepalive_interval.is_some()),
+            ("--h2-metrics", args.h2_metrics),
+            ("--flow-collector", args.flow_collector.is_some()),
+        ];
+        if let Some((flag, _)) = target_only.iter().find(|(_, set)| *set) {
+            return Err(format!("{} cannot be used with --mode echo: no target is dialed", flag));
+        }
+    }
+
+    // FTP replies are rewritten line by line, so the reply stream must reach the rewriter intact.
+    // The client speaks FTP from its first byte and waits for the server's greeting, so nothing
+    // may be injected into, held back from or cut out of the control channel either.
+    if args.mode == Mode::Ftp {
+        let handshake: [(&str, bool); 8] = [
+            ("--payload-preset", args.payload_preset.is_some()),
+            ("--payload", args.payload.is_some()),
+            ("--payload-stage", !args.payload_stages.is_empty()),
+            ("--inject-if-prefix", !args.inject_if_prefix.is_empty()),
+            ("--expect-pattern", args.expect_pattern.is_some()),
+            ("--skip", args.skip > 0),
+            ("--skip-bytes", args.skip_bytes > 0),
+            ("--hello-fragment-size", args.hello_fragment_size > 0),
+        ];
+        if let Some((flag, _)) = handshake.iter().find(|(_, set)| *set) {
+            return Err(format!("{} cannot be used with --mode ftp: the control channel is passed through as FTP", flag));
+        }
+        if args.strip_response_lines > 0 || args.strip_response_bytes > 0 {
+            return Err("--strip-response-lines/--strip-response-bytes cannot be used with --mode ftp: they would cut into the FTP replies".to_string());
+        }
+        if args.h2_metrics {
+            return Err("--h2-metrics cannot be used with --mode ftp: FTP is not HTTP/2".to_string());
+        }
+        if args.upstream_socks5.is_some() {
+            return Err("--upstream-socks5 cannot be used with --mode ftp: data connections are relayed straight to the FTP server".to_string());
+        }
+    }
+
+    if args.busy_response.is_some() && args.max_connections == 0 && args.max_connections_per_ip == 0 {
+        return Err("--busy-response needs --max-connections or --max-connections-per-ip: no client would be refused".to_string());
+    }
+    if args.backup_listen_addr == Some(args.listen_addr) {
+        return Err("--backup-listen-addr must differ from --listen-addr".to_string());
+    }
+    if args.knock_ports.contains(&args.listen_port) {
+        return Err(format!("--knock cannot use the listen port {}", args.listen_port));
+    }
+    if args.max_handshake_bytes > 0 && args.skip == 0 {
+        return Err("--max-handshake-bytes needs --skip: it limits the bytes of skipped packets".to_string());
+    }
+    if args.reserved_connections > args.max_connections {
+        return Err(format!("--reserved-connections ({}) cannot exceed --max-connections ({})", args.reserved_connections, args.max_connections));
+    }
+    if args.reserved_connections > 0 && args.reserve_allow.is_empty() {
+        return Err("--reserved-connections needs --reserve-allow: no client could use the reserved slots".to_string());
+    }
+
+    Ok(())
+}
+
+/// Fills in the byte strings given with escapes on the command line: the payload, the keepalive
+/// payload and the reject and busy responses.
+///
+/// Without `--payload-preset`, FTP clients get no payload, since the server's greeting has to
+/// be the first thing they read; everyone else gets `ws-101`.
+pub fn resolve_payloads(args: &mut Args) -> Result<(), String> {
+    let preset: PayloadPreset = args.payload_preset.unwrap_or(if args.mode == Mode::Ftp { PayloadPreset::SniBug } else { PayloadPreset::Ws101 });
+    args.payload_bytes = preset.bytes(args.payload.as_deref())?;
+    if let Some(keepalive) = args.client_keepalive_payload.as_deref() {
+        args.client_keepalive_bytes = unescape(keepalive)?;
+    }
+    if let Some(reject) = args.reject_payload.as_deref() {
+        args.reject_bytes = unescape(reject)?;
+    }
+    if let Some(busy) = args.busy_response.as_deref() {
+        args.busy_response_bytes = unescape(busy)?;
+    }
+    Ok(())
+}
+
+/// Fills in a target templated on the listen port: `{listen_port}` in the host and `--target-port-offset`.
+pub fn resolve_target_template(args: &mut Args) -> Result<(), String> {
+    args.target_host = args.target_host.replace("{listen_port}", &args.listen_port.to_string());
+    if let Some(offset) = args.target_port_offset {
+        args.target_port = u16::try_from(i32::from(args.listen_port) + offset)
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| format!("listen port {} with offset {} is not a valid target port", args.listen_port, offset))?;
+    }
+    Ok(())
+}
+
+/// Named fake responses for common injector setups.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadPreset {
+    /// A WebSocket-style `101 Switching Protocols` with a huge `Content-Length`.
+    #[value(name = "ws-101")]
+    Ws101,
+    /// A plain `200 Connection Established`, as returned to HTTP CONNECT requests.
+    #[value(name = "http-200")]
+    Http200,
+    /// No response at all, for SNI bug hosts where the client starts TLS immediately.
+    SniBug,
+    /// The bytes given with `--payload`.
+    Custom,
+}
+
+impl PayloadPreset {
+    /// Returns the bytes to send for this preset, using `custom` for `PayloadPreset::Custom`.
+    pub fn bytes(self, custom: Option<&str>) -> Result<Vec<u8>, String> {
+        match self {
+            PayloadPreset::Ws101 => Ok(b"HTTP/1.1 101 Switching Protocols\r\nContent-Length: 1048576000000\r\n\r\n".to_vec()),
+            PayloadPreset::Http200 => Ok(b"HTTP/1.1 200 Connection Established\r\n\r\n".to_vec()),
+            PayloadPreset::SniBug => Ok(Vec::new()),
+            PayloadPreset::Custom => unescape(custom.unwrap_or_default()),
+        }
+    }
+}
+
+/// Expands `\r`, `\n`, `\t`, `\\` and `\xNN` escape sequences in a payload given on the command line.
+pub fn unescape(input: &str) -> Result<Vec<u8>, String> {
+    let mut output: Vec<u8> = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            output.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'r') => output.push(b'\r'),
+            Some(b'n') => output.push(b'\n'),
+            Some(b't') => output.push(b'\t'),
+            Some(b'\\') => output.push(b'\\'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let value: u8 = std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|h| h.len() == 2)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| format!("invalid \\x escape in payload: {:?}", input))?;
+                output.push(value);
+            }
+            Some(other) => return Err(format!("unknown escape \\{} in payload", other as char)),
+            None => return Err("payload ends with a lone backslash".to_string()),
+        }
+    }
+
+    Ok(output)
+}
+
+/// Parses a percentage such as `1%` or `0.5`, which must be above 0 and at most 100.
+pub fn parse_percent(input: &str) -> Result<f64, String> {
+    let percent: f64 = input.trim_end_matches('%').parse().map_err(|_| format!("expected a percentage such as 1%, got {:?}", input))?;
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(format!("percentage must be above 0 and at most 100, got {}", input));
+    }
+    Ok(percent)
+}
+
+/// One step of a multi-stage payload exchange with the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayloadStage {
+    /// Write these bytes to the client.
+    Send(Vec<u8>),
+    /// Read from the client until these bytes have been seen.
+    Expect(Vec<u8>),
+}
+
+/// Parses a `send:BYTES` or `expect:BYTES` payload stage given on the command line.
+pub fn parse_payload_stage(input: &str) -> Result<PayloadStage, String> {
+    match input.split_once(':') {
+        Some(("send", bytes)) => Ok(PayloadStage::Send(unescape(bytes)?)),
+        Some(("expect", "")) => Err("an expect stage needs something to wait for".to_string()),
+        Some(("expect", bytes)) => Ok(PayloadStage::Expect(unescape(bytes)?)),
+        _ => Err(format!("expected send:BYTES or expect:BYTES, got {:?}", input)),
+    }
+}
+
+/// A daily window of local time, stored as minutes since midnight with an exclusive end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    start: u16,
+    end: u16,
+}
+
+impl TimeWindow {
+    /// Returns `true` if `minute` (minutes since local midnight) falls inside the window.
+    pub fn contains(&self, minute: u16) -> bool {
+        if self.start < self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Parses an `HH:MM-HH:MM` time window given on the command line.
+pub fn parse_time_window(input: &str) -> Result<TimeWindow, String> {
+    let parse_time = |time: &str| -> Result<u16, String> {
+        let (hours, minutes) = time.split_once(':').ok_or_else(|| format!("expected HH:MM, got {:?}", time))?;
+        let hours: u16 = hours.parse().ok().filter(|h| *h < 24).ok_or_else(|| format!("invalid hour in {:?}", time))?;
+        let minutes: u16 = minutes.parse().ok().filter(|m| *m < 60).ok_or_else(|| format!("invalid minute in {:?}", time))?;
+        Ok(hours * 60 + minutes)
+    };
+
+    let (start, end) = input.split_once('-').ok_or_else(|| format!("expected HH:MM-HH:MM, got {:?}", input))?;
+    let window = TimeWindow { start: parse_time(start.trim())?, end: parse_time(end.trim())? };
+    if window.start == window.end {
+        return Err("time window start and end must differ".to_string());
+    }
+    Ok(window)
+}
+
+/// An IP address range in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Returns `true` if `ip` falls inside the range; IPv4-mapped IPv6 addresses match IPv4 ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip: IpAddr = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask: u32 = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask: u128 = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a DNS server given as `IP` or `IP:PORT`, defaulting to port 53.
+pub fn parse_dns_server(input: &str) -> Result<SocketAddr, String> {
+    input
+        .parse::<SocketAddr>()
+        .or_else(|_| input.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("invalid DNS server {:?}: expected IP or IP:PORT", input))
+}
+
+/// Parses an address or `address/prefix` range given on the command line.
+pub fn parse_cidr(input: &str) -> Result<Cidr, String> {
+    let (addr, prefix) = match input.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (input, None),
+    };
+    let addr: IpAddr = addr.parse().map_err(|_| format!("invalid address in {:?}", input))?;
+    let max: u8 = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix: u8 = match prefix {
+        Some(prefix) => prefix.parse().ok().filter(|p| *p <= max).ok_or_else(|| format!("invalid prefix length in {:?}", input))?,
+        None => max,
+    };
+    Ok(Cidr { addr, prefix })
+}
+
+/// Destinations for log output.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Info to stdout, warnings and errors to stderr.
+    Stdout,
+    /// Append every line to `--log-file`.
+    File,
+    /// The local syslog daemon, with `--syslog-facility` and `--log-tag`.
+    Syslog,
+    /// The systemd journal's native socket, with `--log-tag` as the identifier.
+    Journald,
+}
+
+/// The syslog facilities a daemon would normally log under.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyslogFacility {
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+#[cfg(unix)]
+impl SyslogFacility {
+    /// Returns the `LOG_*` facility code.
+    pub fn code(self) -> libc::c_int {
+        match self {
+            SyslogFacility::User => libc::LOG_USER,
+            SyslogFacility::Daemon => libc::LOG_DAEMON,
+            SyslogFacility::Local0 => libc::LOG_LOCAL0,
+            SyslogFacility::Local1 => libc::LOG_LOCAL1,
+            SyslogFacility::Local2 => libc::LOG_LOCAL2,
+            SyslogFacility::Local3 => libc::LOG_LOCAL3,
+            SyslogFacility::Local4 => libc::LOG_LOCAL4,
+            SyslogFacility::Local5 => libc::LOG_LOCAL5,
+            SyslogFacility::Local6 => libc::LOG_LOCAL6,
+            SyslogFacility::Local7 => libc::LOG_LOCAL7,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `flags` as a command line and validates it.
+    fn check(flags: &[&str]) -> Result<(), String> {
+        let args: Args = Args::try_parse_from(std::iter::once("proxy-stream").chain(flags.iter().copied())).map_err(|e| e.to_string())?;
+        validate(&args)
+    }
+
+    /// Asserts that `flags` are rejected with an error naming `flag`.
+    fn assert_rejected(flags: &[&str], flag: &str) {
+        let error: String = check(flags).expect_err(&format!("{:?} should be rejected", flags));
+        assert!(error.starts_with(flag), "{:?} was rejected with {:?}", flags, error);
+    }
+
+    #[test]
+    fn plain_modes_are_accepted() {
+        assert_eq!(check(&[]), Ok(()));
+        assert_eq!(check(&["--mode", "echo", "--skip", "1", "--payload-preset", "http-200"]), Ok(()));
+        assert_eq!(check(&["--mode", "ftp", "-p", "21", "--max-connections", "10"]), Ok(()));
+    }
+
+    #[test]
+    fn echo_rejects_target_only_options() {
+        let cases: [(&[&str], &str); 22] = [
+            (&["--target-port-offset", "1"], "--target-port-offset"),
+            (&["--self-address", "192.0.2.1:8888"], "--self-address"),
+            (&["--proxy-protocol"], "--proxy-protocol"),
+            (&["--hello-fragment-size", "8"], "--hello-fragment-size"),
+            (&["--hello-fragment-size", "0", "--hello-fragment-delay-ms", "5"], "--hello-fragment-delay-ms"),
+            (&["--coalesce"], "--coalesce"),
+            (&["--dscp", "46"], "--dscp"),
+            (&["--max-bytes-per-conn", "100"], "--max-bytes-per-conn"),
+            (&["--record-dir", "/tmp"], "--record-dir"),
+            (&["--record-dir", "/tmp", "--inspect-sample", "1%"], "--record-dir"),
+            (&["--dns-fallback", "192.0.2.53"], "--dns-fallback"),
+            (&["--dns-ttl-override", "60"], "--dns-ttl-override"),
+            (&["--upstream-socks5", "127.0.0.1:1080"], "--upstream-socks5"),
+            (&["--expect-banner", "^SSH-"], "--expect-banner"),
+            (&["--on-connect", "true"], "--on-connect"),
+            (&["--on-close", "true"], "--on-close"),
+            (&["--max-upstream-dials", "4"], "--max-upstream-dials"),
+            (&["--strip-response-lines", "1"], "--strip-response-lines"),
+            (&["--strip-response-bytes", "1"], "--strip-response-bytes"),
+            (&["--client-keepalive-interval", "30", "--client-keepalive-payload", "\\n"], "--client-keepalive-interval"),
+            (&["--h2-metrics"], "--h2-metrics"),
+            (&["--flow-collector", "127.0.0.1:2055"], "--flow-collector"),
+        ];
+        for (flags, flag) in cases {
+            assert_rejected(&[&["--mode", "echo"], flags].concat(), flag);
+        }
+    }
+
+    #[test]
+    fn ftp_rejects_payload_preset() {
+        assert_rejected(&["--mode", "ftp", "--payload-preset", "http-200"], "--payload-preset");
+    }
+
+    #[test]
+    fn ftp_rejects_custom_payload() {
+        assert_rejected(&["--mode", "ftp", "--payload", "hi"], "--payload");
+    }
+
+    #[test]
+    fn ftp_rejects_payload_stages() {
+        assert_rejected(&["--mode", "ftp", "--payload-stage", "send:hi"], "--payload-stage");
+    }
+
+    #[test]
+    fn ftp_rejects_conditional_injection() {
+        assert_rejected(&["--mode", "ftp", "--inject-if-prefix", "GET"], "--inject-if-prefix");
+    }
+
+    #[test]
+    fn ftp_rejects_client_patterns() {
+        assert_rejected(&["--mode", "ftp", "--expect-pattern", "^USER"], "--expect-pattern");
+    }
+
+    #[test]
+    fn ftp_rejects_packet_skip() {
+        assert_rejected(&["--mode", "ftp", "--skip", "1"], "--skip");
+    }
+
+    #[test]
+    fn ftp_rejects_byte_skip() {
+        assert_rejected(&["--mode", "ftp", "--skip-bytes", "4"], "--skip-bytes");
+    }
+
+    #[test]
+    fn ftp_rejects_hello_fragmenting() {
+        assert_rejected(&["--mode", "ftp", "--hello-fragment-size", "8"], "--hello-fragment-size");
+    }
+
+    #[test]
+    fn ftp_rejects_response_stripping() {
+        assert_rejected(&["--mode", "ftp", "--strip-response-lines", "1"], "--strip-response-lines");
+        assert_rejected(&["--mode", "ftp", "--strip-response-bytes", "1"], "--strip-response-lines");
+    }
+
+    #[test]
+    fn ftp_rejects_h2_metrics() {
+        assert_rejected(&["--mode", "ftp", "--h2-metrics"], "--h2-metrics");
+    }
+
+    #[test]
+    fn ftp_rejects_socks_upstream() {
+        assert_rejected(&["--mode", "ftp", "--upstream-socks5", "127.0.0.1:1080"], "--upstream-socks5");
+    }
+
+    #[test]
+    fn busy_response_needs_a_connection_limit() {
+        assert_rejected(&["--busy-response", "busy"], "--busy-response");
+        assert_eq!(check(&["--busy-response", "busy", "--max-connections-per-ip", "2"]), Ok(()));
+    }
+
+    #[test]
+    fn backup_listen_addr_must_differ() {
+        assert_rejected(&["--listen-addr", "127.0.0.1", "--backup-listen-addr", "127.0.0.1"], "--backup-listen-addr");
+    }
+
+    #[test]
+    fn knock_ports_must_not_include_the_listen_port() {
+        assert_rejected(&["--listen-port", "8888", "--knock", "7000", "--knock", "8888"], "--knock");
+    }
+
+    #[test]
+    fn handshake_limit_needs_skip() {
+        assert_rejected(&["--max-handshake-bytes", "100"], "--max-handshake-bytes");
+    }
+
+    #[test]
+    fn reserved_connections_are_bounded() {
+        assert_rejected(&["--reserved-connections", "5", "--max-connections", "2", "--reserve-allow", "10.0.0.0/8"], "--reserved-connections");
+        assert_rejected(&["--reserved-connections", "1", "--max-connections", "2"], "--reserved-connections");
+    }
+}
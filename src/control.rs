@@ -0,0 +1,604 @@
+//! The control socket and the `ctl` subcommand that talks to it.
+//!
+//! Each message is a 4-byte big-endian length followed by a JSON object. Requests name
+//! a `command` (`status`, `connections`, `kill` with an `id`, or `drain` and `undrain` with an `addr`); every
+//! response carries `ok` and, when that is `false`, an `error`.
+
+use crate::config::{Args, CtlAction, CtlArgs};
+use crate::histogram::Histogram;
+use crate::listener::Stats;
+use crate::logging::{write_json_string, LogValue};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The largest request the server accepts, in bytes.
+pub const MAX_CONTROL_REQUEST: usize = 64 * 1024;
+
+/// The largest response `ctl` accepts, in bytes; a `connections` listing grows with the number of live connections.
+pub const MAX_CONTROL_RESPONSE: usize = 256 * 1024 * 1024;
+
+/// Reads one length-prefixed message of at most `max` bytes.
+pub async fn read_message<R: AsyncRead + Unpin>(stream: &mut R, max: usize) -> io::Result<String> {
+    let length: usize = stream.read_u32().await? as usize;
+    if length > max {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("control message of {} bytes is too large", length)));
+    }
+    let mut message: Vec<u8> = vec![0; length];
+    stream.read_exact(&mut message).await?;
+    String::from_utf8(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes one length-prefixed message.
+pub async fn write_message<W: AsyncWrite + Unpin>(stream: &mut W, message: &str) -> io::Result<()> {
+    stream.write_u32(message.len() as u32).await?;
+    stream.write_all(message.as_bytes()).await
+}
+
+/// Formats `fields` as a JSON object.
+pub fn json_object(fields: &[(&str, &dyn LogValue)]) -> String {
+    let mut out: String = String::from("{");
+    for (i, (key, value)) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_json_string(&mut out, key);
+        out.push(':');
+        value.write_json(&mut out);
+    }
+    out.push('}');
+    out
+}
+
+/// A JSON value that is already encoded.
+pub struct RawJson(pub String);
+
+impl LogValue for RawJson {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(&self.0);
+    }
+}
+
+/// Parses a flat JSON object whose values are strings or non-negative integers.
+///
+/// Integers are returned in their decimal form; anything else, including a repeated key,
+/// is rejected, which is all the control protocol's requests need.
+pub fn parse_flat_object(input: &str) -> Option<HashMap<String, String>> {
+    let mut chars = input.trim().chars().peekable();
+    let mut fields: HashMap<String, String> = HashMap::new();
+
+    // Reads the four hex digits of a `\u` escape.
+    let read_hex4 = |chars: &mut std::iter::Peekable<std::str::Chars<'_>>| -> Option<u32> {
+        (0..4).try_fold(0, |code, _| Some(code * 16 + chars.next()?.to_digit(16)?))
+    };
+    // Reads a string whose opening quote has already been consumed.
+    let read_string = |chars: &mut std::iter::Peekable<std::str::Chars<'_>>| -> Option<String> {
+        let mut value: String = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(value),
+                '\\' => match chars.next()? {
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    'b' => value.push('\u{8}'),
+                    'f' => value.push('\u{c}'),
+                    'u' => {
+                        let unit: u32 = read_hex4(chars)?;
+                        let code: u32 = match unit {
+                            // A high surrogate must be followed by an escaped low one.
+                            0xD800..=0xDBFF => {
+                                if chars.next()? != '\\' || chars.next()? != 'u' {
+                                    return None;
+                                }
+                                let low: u32 = read_hex4(chars)?;
+                                if !(0xDC00..=0xDFFF).contains(&low) {
+                                    return None;
+                                }
+                                0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                            }
+                            _ => unit,
+                        };
+                        value.push(char::from_u32(code)?);
+                    }
+                    c @ ('"' | '\\' | '/') => value.push(c),
+                    _ => return None,
+                },
+                c if c < ' ' => return None,
+                c => value.push(c),
+            }
+        }
+    };
+    let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::Chars<'_>>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return chars.next().is_none().then_some(fields);
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        if chars.next()? != '"' {
+            return None;
+        }
+        let key: String = read_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+        let value: String = if chars.next_if_eq(&'"').is_some() {
+            read_string(&mut chars)?
+        } else {
+            let digits: String = std::iter::from_fn(|| chars.next_if(|c| c.is_ascii_digit())).collect();
+            if digits.is_empty() || (digits.len() > 1 && digits.starts_with('0')) {
+                return None;
+            }
+            digits
+        };
+        // A repeated key is ambiguous, so it is rejected rather than letting one copy win.
+        if fields.insert(key, value).is_some() {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => return chars.next().is_none().then_some(fields),
+            _ => return None,
+        }
+    }
+}
+
+/// Point-in-time resource usage, for spotting leaks.
+pub struct Gauges {
+    /// The number of tasks alive on the runtime.
+    pub tasks: usize,
+    /// The bytes allocated on the heap.
+    pub heap_bytes: usize,
+    /// The bytes held in connection buffers, counted as they are allocated and freed.
+    pub buffer_capacity_bytes: usize,
+    /// The soft open file limit, if it could be read at startup.
+    pub nofile_limit: Option<u64>,
+    /// The estimated file descriptors in use: two per active connection.
+    pub descriptors_in_use: u64,
+}
+
+impl Gauges {
+    /// Reads the gauges; must be called from within the runtime.
+    pub fn read(stats: &Stats) -> Self {
+        Gauges {
+            tasks: tokio::runtime::Handle::current().metrics().num_alive_tasks(),
+            heap_bytes: crate::memory::heap_bytes(),
+            buffer_capacity_bytes: crate::memory::buffer_bytes(),
+            nofile_limit: Some(stats.nofile_limit.load(Ordering::Relaxed)).filter(|limit| *limit > 0),
+            descriptors_in_use: stats.active_connections.load(Ordering::Relaxed) * 2,
+        }
+    }
+}
+
+/// Returns the live connections as `(id, client, age in seconds)`, ordered by id.
+pub fn live_connections(stats: &Stats) -> Vec<(u64, SocketAddr, u64)> {
+    let now: u64 = crate::flow::unix_millis();
+    let mut connections: Vec<(u64, SocketAddr, u64)> =
+        stats.connections.lock().unwrap().iter().map(|(id, live)| (*id, live.client, now.saturating_sub(live.started_ms) / 1000)).collect();
+    connections.sort_unstable_by_key(|(id, _, _)| *id);
+    connections
+}
+
+/// Formats the live connections as a JSON array.
+fn connections_json(stats: &Stats) -> RawJson {
+    let list: Vec<String> =
+        live_connections(stats).iter().map(|(id, client, age_secs)| json_object(&[("id", id), ("client", client), ("age_secs", age_secs)])).collect();
+    RawJson(format!("[{}]", list.join(",")))
+}
+
+/// Formats the per-resolver lookup counters as a JSON array.
+fn resolvers_json(stats: &Stats) -> RawJson {
+    let list: Vec<String> = stats
+        .resolvers
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(resolver, (queries, failures))| json_object(&[("resolver", &resolver.as_str()), ("queries", queries), ("failures", failures)]))
+        .collect();
+    RawJson(format!("[{}]", list.join(",")))
+}
+
+/// Formats the target addresses with live connections or a drain in place as a JSON array.
+fn targets_json(stats: &Stats) -> RawJson {
+    let list: Vec<String> = target_counts(stats)
+        .iter()
+        .map(|(addr, connections, draining)| json_object(&[("addr", addr), ("connections", connections), ("draining", draining)]))
+        .collect();
+    RawJson(format!("[{}]", list.join(",")))
+}
+
+/// Returns each target address that has live connections or is draining as `(address, connections, draining)`, ordered by address.
+fn target_counts(stats: &Stats) -> Vec<(SocketAddr, u64, bool)> {
+    let targets = stats.targets.lock().unwrap();
+    let draining = stats.draining.lock().unwrap();
+    let mut addrs: Vec<SocketAddr> = targets.keys().chain(draining.iter()).copied().collect();
+    addrs.sort_unstable();
+    addrs.dedup();
+    addrs.into_iter().map(|addr| (addr, targets.get(&addr).copied().unwrap_or(0), draining.contains(&addr))).collect()
+}
+
+/// The connection histograms reported by `status` and stats dumps, by name.
+fn histograms(stats: &Stats) -> [(&'static str, &Histogram); 4] {
+    [
+        ("connect_ms", &stats.connect_ms),
+        ("duration_ms", &stats.duration_ms),
+        ("transfer_bytes", &stats.transfer_bytes),
+        ("throughput_bps", &stats.throughput_bps),
+    ]
+}
+
+/// Formats the count and p50/p95/p99 of each connection histogram as a JSON object.
+fn histograms_json(stats: &Stats) -> RawJson {
+    let fields: Vec<String> = histograms(stats)
+        .iter()
+        .map(|(name, histogram)| {
+            let summary: String =
+                json_object(&[("count", &histogram.count()), ("p50", &histogram.percentile(50.0)), ("p95", &histogram.percentile(95.0)), ("p99", &histogram.percentile(99.0))]);
+            let mut field: String = String::new();
+            write_json_string(&mut field, name);
+            field.push(':');
+            field.push_str(&summary);
+            field
+        })
+        .collect();
+    RawJson(format!("{{{}}}", fields.join(",")))
+}
+
+/// Logs a snapshot of the counters and live connections, and writes it to `--stats-file` as JSON if set.
+///
+/// This is what SIGUSR1 triggers, for operators without a control socket.
+pub fn dump_stats(args: &Args, stats: &Stats) {
+    let active: u64 = stats.active_connections.load(Ordering::Relaxed);
+    let accept_errors: u64 = stats.accept_errors.load(Ordering::Relaxed);
+    let h2_streams: u64 = stats.h2_streams.load(Ordering::Relaxed);
+    let h2_resets: u64 = stats.h2_resets.load(Ordering::Relaxed);
+    let gauges: Gauges = Gauges::read(stats);
+    log!(
+        Info,
+        "stats",
+        {
+            "active_connections" => active,
+            "accept_errors" => accept_errors,
+            "h2_streams" => h2_streams,
+            "h2_resets" => h2_resets,
+            "tasks" => gauges.tasks,
+            "heap_bytes" => gauges.heap_bytes,
+            "buffer_capacity_bytes" => gauges.buffer_capacity_bytes,
+            "nofile_limit" => gauges.nofile_limit,
+            "descriptors_in_use" => gauges.descriptors_in_use
+        },
+        "Stats: {} active connections, {} accept errors, {} HTTP/2 streams, {} HTTP/2 resets, {} tasks, {} heap bytes, {} bytes of buffer capacity, about {} of {} file descriptors in use",
+        active,
+        accept_errors,
+        h2_streams,
+        h2_resets,
+        gauges.tasks,
+        gauges.heap_bytes,
+        gauges.buffer_capacity_bytes,
+        gauges.descriptors_in_use,
+        gauges.nofile_limit.map_or("an unknown number".to_string(), |limit| limit.to_string())
+    );
+    for (resolver, (queries, failures)) in stats.resolvers.lock().unwrap().iter() {
+        log!(Info, "stats_resolver", { "resolver" => resolver.as_str(), "queries" => *queries, "failures" => *failures }, "Resolver {}: {} lookups, {} failed", resolver, queries, failures);
+    }
+    for (addr, connections, draining) in target_counts(stats) {
+        log!(Info, "stats_target", { "addr" => addr, "connections" => connections, "draining" => draining }, "Target {}: {} connections{}", addr, connections, if draining { ", draining" } else { "" });
+    }
+    for (name, histogram) in histograms(stats) {
+        let (count, p50, p95, p99) = (histogram.count(), histogram.percentile(50.0), histogram.percentile(95.0), histogram.percentile(99.0));
+        log!(Info, "stats_histogram", { "name" => name, "count" => count, "p50" => p50, "p95" => p95, "p99" => p99 }, "Histogram {}: {} connections, p50 {}, p95 {}, p99 {}", name, count, p50, p95, p99);
+    }
+    for (id, client, age_secs) in live_connections(stats) {
+        log!(Info, "stats_connection", { "id" => id, "client" => client, "age_secs" => age_secs }, "Connection {}: {}:{} for {}s", id, client.ip(), client.port(), age_secs);
+    }
+
+    if let Some(path) = args.stats_file.as_deref() {
+        let snapshot: String = json_object(&[
+            ("listen_port", &args.listen_port),
+            ("active_connections", &active),
+            ("accept_errors", &accept_errors),
+            ("h2_streams", &h2_streams),
+            ("h2_resets", &h2_resets),
+            ("tasks", &gauges.tasks),
+            ("heap_bytes", &gauges.heap_bytes),
+            ("buffer_capacity_bytes", &gauges.buffer_capacity_bytes),
+            ("nofile_limit", &gauges.nofile_limit),
+            ("descriptors_in_use", &gauges.descriptors_in_use),
+            ("resolvers", &resolvers_json(stats)),
+            ("targets", &targets_json(stats)),
+            ("histograms", &histograms_json(stats)),
+            ("connections", &connections_json(stats)),
+        ]);
+        if let Err(e) = std::fs::write(path, snapshot + "\n") {
+            log!(Warn, "stats_file_failed", { "path" => path.display().to_string(), "error" => e }, "Failed to write stats to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Builds an error response.
+fn error_response(message: &str) -> String {
+    json_object(&[("ok", &false), ("error", &message)])
+}
+
+/// Answers one control request.
+pub fn handle_request(request: &str, args: &Args, stats: &Stats) -> String {
+    let Some(fields) = parse_flat_object(request) else {
+        return error_response("request is not a JSON object of strings and integers");
+    };
+
+    match fields.get("command").map(String::as_str) {
+        Some("status") => {
+            let gauges: Gauges = Gauges::read(stats);
+            json_object(&[
+                ("ok", &true),
+                ("listen_port", &args.listen_port),
+                ("active_connections", &stats.active_connections.load(Ordering::Relaxed)),
+                ("accept_errors", &stats.accept_errors.load(Ordering::Relaxed)),
+                ("h2_streams", &stats.h2_streams.load(Ordering::Relaxed)),
+                ("h2_resets", &stats.h2_resets.load(Ordering::Relaxed)),
+                ("tasks", &gauges.tasks),
+                ("heap_bytes", &gauges.heap_bytes),
+                ("buffer_capacity_bytes", &gauges.buffer_capacity_bytes),
+                ("nofile_limit", &gauges.nofile_limit),
+                ("descriptors_in_use", &gauges.descriptors_in_use),
+                ("resolvers", &resolvers_json(stats)),
+                ("targets", &targets_json(stats)),
+                ("histograms", &histograms_json(stats)),
+            ])
+        }
+        Some("connections") => json_object(&[("ok", &true), ("connections", &connections_json(stats))]),
+        Some("kill") => {
+            let Some(id) = fields.get("id").and_then(|id| id.parse::<u64>().ok()) else {
+                return error_response("kill needs a numeric id");
+            };
+            match stats.connections.lock().unwrap().get(&id) {
+                Some(live) => {
+                    live.stop.notify_one();
+                    json_object(&[("ok", &true), ("id", &id)])
+                }
+                None => error_response(&format!("no connection with id {}", id)),
+            }
+        }
+        Some(command @ ("drain" | "undrain")) => {
+            let Some(addr) = fields.get("addr").and_then(|addr| addr.parse::<SocketAddr>().ok()) else {
+                return error_response(&format!("{} needs an addr such as \"192.0.2.1:443\"", command));
+            };
+            let active: u64 = stats.targets.lock().unwrap().get(&addr).copied().unwrap_or(0);
+            if command == "drain" {
+                stats.draining.lock().unwrap().insert(addr);
+                log!(Info, "target_draining", { "addr" => addr, "active" => active }, "Draining target {}: {} connections left", addr, active);
+                if active == 0 {
+                    log!(Info, "target_drained", { "addr" => addr }, "Target {} is drained: no connections left", addr);
+                }
+            } else {
+                stats.draining.lock().unwrap().remove(&addr);
+                log!(Info, "target_undrained", { "addr" => addr }, "Target {} takes new connections again", addr);
+            }
+            json_object(&[("ok", &true), ("addr", &addr), ("active", &active)])
+        }
+        Some(other) => error_response(&format!("unknown command {:?}", other)),
+        None => error_response("request has no command"),
+    }
+}
+
+/// Serves the control socket at `path` until the listener fails.
+///
+/// A socket file left behind by an earlier run is replaced; any other file at `path` is an error.
+#[cfg(unix)]
+pub async fn serve_control(path: &Path, args: Arc<Args>, stats: Arc<Stats>) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener: tokio::net::UnixListener = tokio::net::UnixListener::bind(path)?;
+    log!(Info, "control_socket", { "path" => path.display().to_string() }, "Control socket listening at {}", path.display());
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let args: Arc<Args> = Arc::clone(&args);
+        let stats: Arc<Stats> = Arc::clone(&stats);
+        tokio::spawn(async move {
+            while let Ok(request) = read_message(&mut stream, MAX_CONTROL_REQUEST).await {
+                let response: String = handle_request(&request, &args, &stats);
+                if write_message(&mut stream, &response).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// The control socket is only supported on Unix platforms.
+#[cfg(not(unix))]
+pub async fn serve_control(_path: &Path, _args: Arc<Args>, _stats: Arc<Stats>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "the control socket is not available on this platform"))
+}
+
+/// Runs the `ctl` subcommand: sends one request and prints the response.
+///
+/// Returns `Ok(false)` if the daemon reported an error.
+pub async fn ctl(args: CtlArgs) -> Result<bool, Box<dyn std::error::Error>> {
+    let request: String = match args.action {
+        CtlAction::Status => json_object(&[("command", &"status")]),
+        CtlAction::Connections => json_object(&[("command", &"connections")]),
+        CtlAction::Kill { id } => json_object(&[("command", &"kill"), ("id", &id)]),
+        CtlAction::Drain { addr } => json_object(&[("command", &"drain"), ("addr", &addr)]),
+        CtlAction::Undrain { addr } => json_object(&[("command", &"undrain"), ("addr", &addr)]),
+    };
+
+    let response: String = exchange(&args.socket, &request).await?;
+    println!("{}", response);
+
+    // Responses always lead with `ok`.
+    Ok(response.starts_with("{\"ok\":true"))
+}
+
+/// Sends `request` to the control socket at `path` and returns the response.
+#[cfg(unix)]
+async fn exchange(path: &Path, request: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut stream: tokio::net::UnixStream = tokio::net::UnixStream::connect(path).await.map_err(|e| format!("failed to connect to {}: {}", path.display(), e))?;
+    write_message(&mut stream, request).await?;
+    Ok(read_message(&mut stream, MAX_CONTROL_RESPONSE).await?)
+}
+
+/// The control socket is only supported on Unix platforms.
+#[cfg(not(unix))]
+async fn exchange(_path: &Path, _request: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Err("the control socket is not available on this platform".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Parses `input` and returns its fields sorted, for comparison.
+    fn parsed(input: &str) -> Option<Vec<(String, String)>> {
+        let mut fields: Vec<(String, String)> = parse_flat_object(input)?.into_iter().collect();
+        fields.sort();
+        Some(fields)
+    }
+
+    fn args() -> Args {
+        Args::try_parse_from(["proxy-stream", "-H", "127.0.0.1"]).unwrap()
+    }
+
+    #[test]
+    fn flat_objects_are_parsed() {
+        assert_eq!(parsed(" { } "), Some(vec![]));
+        assert_eq!(
+            parsed("{ \"command\" : \"kill\", \"id\": 42 }"),
+            Some(vec![("command".to_string(), "kill".to_string()), ("id".to_string(), "42".to_string())])
+        );
+        assert_eq!(parsed("{\"id\":0}"), Some(vec![("id".to_string(), "0".to_string())]));
+    }
+
+    #[test]
+    fn escapes_are_decoded() {
+        assert_eq!(parsed(r#"{"k":"a\"b\\c\/d\n\r\t\b\f"}"#), Some(vec![("k".to_string(), "a\"b\\c/d\n\r\t\u{8}\u{c}".to_string())]));
+        assert_eq!(parsed(r#"{"k":"\u00e9\u4E2D"}"#), Some(vec![("k".to_string(), "é中".to_string())]));
+        assert_eq!(parsed(r#"{"k":"\ud83d\ude00"}"#), Some(vec![("k".to_string(), "😀".to_string())]));
+    }
+
+    #[test]
+    fn malformed_escapes_are_rejected() {
+        for input in [
+            r#"{"k":"\x"}"#,
+            r#"{"k":"\u12"}"#,
+            r#"{"k":"\u+123"}"#,
+            r#"{"k":"\u12g4"}"#,
+            r#"{"k":"\ud83d"}"#,
+            r#"{"k":"\ud83dx"}"#,
+            r#"{"k":"\ud83dA"}"#,
+            r#"{"k":"\ud83d\u0041"}"#,
+            r#"{"k":"\ude00"}"#,
+            "{\"k\":\"raw\ncontrol\"}",
+        ] {
+            assert_eq!(parsed(input), None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn malformed_objects_are_rejected() {
+        for input in [
+            "",
+            "{",
+            "{\"command\":\"status",
+            "{\"command\":\"status\"",
+            "{\"command\":\"status\"}x",
+            "{\"command\":\"status\"} {}",
+            "{}}",
+            "{\"a\":1,}",
+            "{\"a\" 1}",
+            "{a:1}",
+            "{\"a\":}",
+            "{\"a\":-1}",
+            "{\"a\":1.5}",
+            "{\"a\":007}",
+            "{\"a\":true}",
+            "{\"a\":null}",
+            "{\"a\":{}}",
+            "[]",
+        ] {
+            assert_eq!(parsed(input), None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn duplicate_keys_are_rejected() {
+        assert_eq!(parsed("{\"command\":\"status\",\"command\":\"kill\"}"), None);
+        assert_eq!(parsed("{\"id\":1,\"id\":1}"), None);
+    }
+
+    #[test]
+    fn requests_with_bad_arguments_are_answered_with_errors() {
+        let args: Args = args();
+        let stats: Stats = Stats::default();
+        assert_eq!(handle_request("not json", &args, &stats), error_response("request is not a JSON object of strings and integers"));
+        assert_eq!(handle_request("{}", &args, &stats), error_response("request has no command"));
+        assert_eq!(handle_request("{\"command\":\"reboot\"}", &args, &stats), error_response("unknown command \"reboot\""));
+        for request in ["{\"command\":\"kill\"}", "{\"command\":\"kill\",\"id\":\"x\"}", "{\"command\":\"kill\",\"id\":99999999999999999999}"] {
+            assert_eq!(handle_request(request, &args, &stats), error_response("kill needs a numeric id"), "{}", request);
+        }
+        assert_eq!(handle_request("{\"command\":\"kill\",\"id\":7}", &args, &stats), error_response("no connection with id 7"));
+        for request in ["{\"command\":\"drain\"}", "{\"command\":\"drain\",\"addr\":\"192.0.2.1\"}", "{\"command\":\"drain\",\"addr\":443}"] {
+            assert_eq!(handle_request(request, &args, &stats), error_response("drain needs an addr such as \"192.0.2.1:443\""), "{}", request);
+        }
+        assert!(stats.draining.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn drain_and_undrain_update_the_target_set() {
+        let args: Args = args();
+        let stats: Stats = Stats::default();
+        let addr: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        assert_eq!(handle_request("{\"command\":\"drain\",\"addr\":\"192.0.2.1:443\"}", &args, &stats), "{\"ok\":true,\"addr\":\"192.0.2.1:443\",\"active\":0}");
+        assert!(stats.draining.lock().unwrap().contains(&addr));
+        handle_request("{\"command\":\"undrain\",\"addr\":\"192.0.2.1:443\"}", &args, &stats);
+        assert!(!stats.draining.lock().unwrap().contains(&addr));
+    }
+
+    #[tokio::test]
+    async fn messages_are_limited_by_their_length_prefix() {
+        let mut at_limit: Vec<u8> = (4u32).to_be_bytes().to_vec();
+        at_limit.extend_from_slice(b"{}  ");
+        assert_eq!(read_message(&mut at_limit.as_slice(), 4).await.unwrap(), "{}  ");
+
+        let mut over_limit: Vec<u8> = ((MAX_CONTROL_REQUEST + 1) as u32).to_be_bytes().to_vec();
+        over_limit.resize(4 + MAX_CONTROL_REQUEST + 1, b' ');
+        assert_eq!(read_message(&mut over_limit.as_slice(), MAX_CONTROL_REQUEST).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut huge: &[u8] = &u32::MAX.to_be_bytes();
+        assert_eq!(read_message(&mut huge, MAX_CONTROL_REQUEST).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut truncated: &[u8] = &[0, 0, 0, 10, b'{', b'}'];
+        assert_eq!(read_message(&mut truncated, MAX_CONTROL_REQUEST).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut invalid_utf8: &[u8] = &[0, 0, 0, 2, 0xff, 0xfe];
+        assert_eq!(read_message(&mut invalid_utf8, MAX_CONTROL_REQUEST).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip() {
+        let mut buffer: Vec<u8> = Vec::new();
+        write_message(&mut buffer, "{\"command\":\"status\"}").await.unwrap();
+        assert_eq!(read_message(&mut buffer.as_slice(), MAX_CONTROL_REQUEST).await.unwrap(), "{\"command\":\"status\"}");
+    }
+}
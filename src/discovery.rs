@@ -0,0 +1,370 @@
+//! Making the listener discoverable and reachable: mDNS, NAT-PMP and STUN.
+
+use crate::config::Args;
+use crate::wire::{put_length_prefixed, random_bytes};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// The mDNS multicast group and port.
+pub const MDNS_GROUP: std::net::Ipv4Addr = std::net::Ipv4Addr::new(224, 0, 0, 251);
+
+pub const MDNS_PORT: u16 = 5353;
+
+/// The DNS-SD service type the listener is advertised under.
+pub const MDNS_SERVICE: &str = "_proxy._tcp.local";
+
+/// The TTL, in seconds, of the advertised records.
+pub const MDNS_TTL: u32 = 120;
+
+/// Opens a UDP socket on the mDNS port, shared with any other responder on the host, and joins the group.
+#[cfg(unix)]
+pub fn mdns_socket() -> io::Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: plain socket creation; the descriptor is owned by `socket` as soon as it is valid.
+    let fd: libc::c_int = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a freshly created socket that nothing else owns.
+    let socket: std::net::UdpSocket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+    let one: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        // SAFETY: the descriptor is valid for the lifetime of `socket`, and `one` outlives the call.
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &one as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: MDNS_PORT.to_be(),
+        sin_addr: libc::in_addr { s_addr: libc::INADDR_ANY },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: `addr` is a valid `sockaddr_in` for the duration of the call.
+    let result = unsafe { libc::bind(fd, &addr as *const libc::sockaddr_in as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    socket.join_multicast_v4(&MDNS_GROUP, &std::net::Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// mDNS is only supported on Unix platforms.
+#[cfg(not(unix))]
+pub fn mdns_socket() -> io::Result<UdpSocket> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "mDNS is not available on this platform"))
+}
+
+/// Returns this machine's host name.
+#[cfg(unix)]
+pub fn host_name() -> io::Result<String> {
+    let mut name: [u8; 256] = [0; 256];
+    // SAFETY: `name` is writable for its full length, and one byte is kept back for the terminator.
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len() - 1) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let end: usize = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Ok(String::from_utf8_lossy(&name[..end]).into_owned())
+}
+
+/// Host names are not available on this platform.
+#[cfg(not(unix))]
+pub fn host_name() -> io::Result<String> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "host names are not available on this platform"))
+}
+
+/// Appends `name` to `out` as uncompressed DNS labels.
+pub fn put_dns_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        put_length_prefixed(out, 1, &label.as_bytes()[..label.len().min(63)]);
+    }
+    out.push(0);
+}
+
+/// Appends a resource record of `kind` for `name` to `out`, with the cache-flush bit if `unique`.
+pub fn put_dns_record(out: &mut Vec<u8>, name: &str, kind: u16, unique: bool, data: &[u8]) {
+    put_dns_name(out, name);
+    out.extend_from_slice(&kind.to_be_bytes());
+    out.extend_from_slice(&(if unique { 0x8001u16 } else { 0x0001 }).to_be_bytes());
+    out.extend_from_slice(&MDNS_TTL.to_be_bytes());
+    put_length_prefixed(out, 2, data);
+}
+
+/// Reads the (possibly compressed) DNS name at `offset`, returning it in lower case and the offset just past it.
+pub fn read_dns_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end: Option<usize> = None;
+    let mut jumps: usize = 0;
+    let mut length_read: usize = 0;
+
+    loop {
+        let length: usize = *message.get(offset)? as usize;
+        match length {
+            0 => return Some((labels.join("."), end.unwrap_or(offset + 1))),
+            l if l & 0xc0 == 0xc0 => {
+                // Bound the number of compression jumps so a malicious pointer loop cannot spin forever.
+                jumps += 1;
+                if jumps > 32 {
+                    return None;
+                }
+                let pointer: usize = ((l & 0x3f) << 8) | *message.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            l if l & 0xc0 != 0 => return None,
+            l => {
+                // A name is at most 255 bytes on the wire, which also bounds the labels read.
+                length_read += 1 + l;
+                if length_read > 255 {
+                    return None;
+                }
+                let label: &[u8] = message.get(offset + 1..offset + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                offset += 1 + l;
+            }
+        }
+    }
+}
+
+/// Returns the lower-cased names asked about in an mDNS query, or nothing for responses.
+pub fn mdns_questions(message: &[u8]) -> Vec<String> {
+    if message.len() < 12 || message[2] & 0x80 != 0 {
+        return Vec::new();
+    }
+    let count: u16 = u16::from_be_bytes([message[4], message[5]]);
+    let mut offset: usize = 12;
+    let mut names: Vec<String> = Vec::new();
+    for _ in 0..count {
+        let Some((name, next)) = read_dns_name(message, offset) else {
+            break;
+        };
+        names.push(name);
+        offset = next + 4;
+    }
+    names
+}
+
+/// Announces the listener via mDNS and answers queries for it until an error occurs.
+///
+/// The PTR, SRV, TXT and A records are announced twice at startup, as RFC 6762 recommends,
+/// and sent again whenever a query asks for the service type, the instance or the host.
+pub async fn advertise_mdns(args: Arc<Args>) -> io::Result<()> {
+    let socket: UdpSocket = mdns_socket()?;
+    let host: String = format!("{}.local", host_name()?.split('.').next().unwrap_or("proxy-stream"));
+    let instance: String = format!("{}.{}", args.mdns_name.replace('.', "-"), MDNS_SERVICE);
+
+    // The address other hosts reach us at is the one the kernel would use towards the group.
+    let probe = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    probe.connect((MDNS_GROUP, MDNS_PORT))?;
+    let ip: std::net::Ipv4Addr = match probe.local_addr()?.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no IPv4 address for mDNS")),
+    };
+
+    // Build the response once; it never changes.
+    let mut txt: Vec<u8> = Vec::new();
+    let mode: String = format!("{:?}", args.mode).to_ascii_lowercase();
+    let defaults: [String; 2] = [format!("version={}", env!("CARGO_PKG_VERSION")), format!("mode={}", mode)];
+    for entry in defaults.iter().chain(args.mdns_txt.iter()) {
+        put_length_prefixed(&mut txt, 1, &entry.as_bytes()[..entry.len().min(255)]);
+    }
+    let mut ptr: Vec<u8> = Vec::new();
+    put_dns_name(&mut ptr, &instance);
+    let mut srv: Vec<u8> = vec![0, 0, 0, 0];
+    srv.extend_from_slice(&args.listen_port.to_be_bytes());
+    put_dns_name(&mut srv, &host);
+
+    let mut response: Vec<u8> = vec![0, 0, 0x84, 0, 0, 0, 0, 4, 0, 0, 0, 0];
+    put_dns_record(&mut response, MDNS_SERVICE, 12, false, &ptr);
+    put_dns_record(&mut response, &instance, 33, true, &srv);
+    put_dns_record(&mut response, &instance, 16, true, &txt);
+    put_dns_record(&mut response, &host, 1, true, &ip.octets());
+
+    let group: SocketAddr = SocketAddr::from((MDNS_GROUP, MDNS_PORT));
+    for _ in 0..2 {
+        socket.send_to(&response, group).await?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    log!(Info, "mdns_advertised", { "instance" => instance, "host" => host }, "Advertising {} on {} via mDNS", instance, host);
+
+    let names: [String; 3] = [MDNS_SERVICE.to_string(), instance.to_ascii_lowercase(), host.to_ascii_lowercase()];
+    let mut buffer: Vec<u8> = vec![0; 9000];
+    loop {
+        let (n, _) = socket.recv_from(&mut buffer).await?;
+        if mdns_questions(&buffer[..n]).iter().any(|question| names.contains(question)) {
+            socket.send_to(&response, group).await?;
+        }
+    }
+}
+
+/// The UDP port NAT-PMP gateways listen on.
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// The lifetime, in seconds, requested for the listener's port mapping; it is renewed at half that.
+pub const NAT_PMP_LIFETIME: u32 = 3600;
+
+/// Returns the IPv4 default gateway from the kernel routing table.
+pub fn default_gateway() -> io::Result<std::net::Ipv4Addr> {
+    let routes: String = std::fs::read_to_string("/proc/net/route")?;
+    routes
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .find(|fields| fields.get(1) == Some(&"00000000"))
+        .and_then(|fields| u32::from_str_radix(fields.get(2)?, 16).ok())
+        // The table prints the address as a native-endian integer of its network-order bytes.
+        .map(|gateway| std::net::Ipv4Addr::from(gateway.to_ne_bytes()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no IPv4 default gateway"))
+}
+
+/// Sends a NAT-PMP request and returns the response, retrying with the backoff from RFC 6886.
+pub async fn nat_pmp_request(socket: &UdpSocket, request: &[u8], opcode: u8) -> io::Result<Vec<u8>> {
+    let mut buffer: [u8; 16] = [0; 16];
+    let mut wait: Duration = Duration::from_millis(250);
+    for _ in 0..6 {
+        socket.send(request).await?;
+        if let Ok(result) = tokio::time::timeout(wait, socket.recv(&mut buffer)).await {
+            let n: usize = result?;
+            if n >= 4 && buffer[1] == opcode + 128 {
+                let code: u16 = u16::from_be_bytes([buffer[2], buffer[3]]);
+                if code != 0 {
+                    return Err(io::Error::other(format!("gateway refused request with result code {}", code)));
+                }
+                return Ok(buffer[..n].to_vec());
+            }
+        }
+        wait *= 2;
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "no reply from the NAT-PMP gateway"))
+}
+
+/// Maps the listen port on the gateway with NAT-PMP and keeps the mapping alive.
+///
+/// The external address and port are logged whenever the mapping is created or changes.
+pub async fn maintain_nat_pmp_mapping(args: Arc<Args>) -> io::Result<()> {
+    let gateway: std::net::Ipv4Addr = match args.nat_pmp_gateway {
+        Some(gateway) => gateway,
+        None => default_gateway()?,
+    };
+    let socket: UdpSocket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NAT_PMP_PORT)).await?;
+
+    let mut mapped: Option<(std::net::Ipv4Addr, u16)> = None;
+    loop {
+        // Opcode 0 asks for the external address, opcode 2 maps a TCP port.
+        let response: Vec<u8> = nat_pmp_request(&socket, &[0, 0], 0).await?;
+        if response.len() < 12 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short NAT-PMP external address response"));
+        }
+        let external_ip = std::net::Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+        let mut request: Vec<u8> = vec![0, 2, 0, 0];
+        request.extend_from_slice(&args.listen_port.to_be_bytes());
+        request.extend_from_slice(&mapped.map_or(args.listen_port, |(_, port)| port).to_be_bytes());
+        request.extend_from_slice(&NAT_PMP_LIFETIME.to_be_bytes());
+        let response: Vec<u8> = nat_pmp_request(&socket, &request, 2).await?;
+        if response.len() < 16 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short NAT-PMP mapping response"));
+        }
+        let external_port: u16 = u16::from_be_bytes([response[10], response[11]]);
+        let lifetime: u32 = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+
+        if mapped != Some((external_ip, external_port)) {
+            log!(
+                Info,
+                "nat_pmp_mapped",
+                { "gateway" => gateway.to_string(), "external" => SocketAddr::from((external_ip, external_port)), "lifetime" => u64::from(lifetime) },
+                "Mapped {}:{} -> port {} via NAT-PMP on {} for {}s",
+                external_ip,
+                external_port,
+                args.listen_port,
+                gateway,
+                lifetime
+            );
+            mapped = Some((external_ip, external_port));
+        }
+        tokio::time::sleep(Duration::from_secs(u64::from(lifetime.max(2) / 2))).await;
+    }
+}
+
+/// The magic cookie in every RFC 5389 STUN message.
+pub const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+
+/// Sends a STUN Binding request to `server` and returns the address it saw the request come from.
+///
+/// The mapping is that of a UDP socket, so only the IP is meaningful for the TCP listener.
+pub async fn stun_public_address(server: &str) -> io::Result<SocketAddr> {
+    let server: SocketAddr = tokio::net::lookup_host(server)
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "STUN server has no IPv4 address"))?;
+    let socket: UdpSocket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(server).await?;
+
+    let transaction: Vec<u8> = random_bytes(12);
+    let mut request: Vec<u8> = vec![0x00, 0x01, 0x00, 0x00];
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction);
+
+    let mut buffer: [u8; 1024] = [0; 1024];
+    let mut wait: Duration = Duration::from_millis(500);
+    for _ in 0..4 {
+        socket.send(&request).await?;
+        let Ok(result) = tokio::time::timeout(wait, socket.recv(&mut buffer)).await else {
+            wait *= 2;
+            continue;
+        };
+        let response: &[u8] = &buffer[..result?];
+
+        // Only a Binding success response to our own transaction counts.
+        if response.len() < 20 || response[..2] != [0x01, 0x01] || response[8..20] != transaction[..] {
+            continue;
+        }
+
+        // Walk the attributes, preferring XOR-MAPPED-ADDRESS over the legacy MAPPED-ADDRESS.
+        let mut mapped: Option<SocketAddr> = None;
+        let mut attributes: &[u8] = &response[20..];
+        while attributes.len() >= 4 {
+            let kind: u16 = u16::from_be_bytes([attributes[0], attributes[1]]);
+            let length: usize = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+            let Some(value) = attributes.get(4..4 + length) else {
+                break;
+            };
+            if value.len() >= 8 && value[1] == 0x01 {
+                let port: u16 = u16::from_be_bytes([value[2], value[3]]);
+                let ip: [u8; 4] = [value[4], value[5], value[6], value[7]];
+                match kind {
+                    0x0020 => {
+                        let cookie: [u8; 4] = STUN_MAGIC_COOKIE.to_be_bytes();
+                        let port: u16 = port ^ (STUN_MAGIC_COOKIE >> 16) as u16;
+                        let ip: [u8; 4] = [ip[0] ^ cookie[0], ip[1] ^ cookie[1], ip[2] ^ cookie[2], ip[3] ^ cookie[3]];
+                        return Ok(SocketAddr::from((ip, port)));
+                    }
+                    0x0001 => mapped = Some(SocketAddr::from((ip, port))),
+                    _ => {}
+                }
+            }
+            // Attributes are padded to a multiple of four bytes.
+            attributes = attributes.get(4 + length.div_ceil(4) * 4..).unwrap_or_default();
+        }
+        return mapped.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "STUN response has no mapped address"));
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "no reply from the STUN server"))
+}
@@ -0,0 +1,140 @@
+//! IPFIX export of per-connection flow records.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// Bytes and reads forwarded in each direction of a connection, for flow export.
+#[derive(Default)]
+pub struct FlowCounters {
+    pub up_bytes: AtomicU64,
+    pub up_packets: AtomicU64,
+    pub down_bytes: AtomicU64,
+    pub down_packets: AtomicU64,
+}
+
+impl FlowCounters {
+    /// Records `n` bytes forwarded from the client to the server.
+    pub fn up(&self, n: usize) {
+        self.up_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        self.up_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records `n` bytes forwarded from the server to the client.
+    pub fn down(&self, n: usize) {
+        self.down_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        self.down_packets.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The IPFIX template used for flows between two IPv4 addresses.
+pub const IPFIX_TEMPLATE_V4: u16 = 256;
+
+/// The IPFIX template used for flows involving an IPv6 address; IPv4 peers are mapped.
+pub const IPFIX_TEMPLATE_V6: u16 = 257;
+
+/// The information elements (ID, length) following the addresses in both templates:
+/// sourceTransportPort, destinationTransportPort, protocolIdentifier, octetDeltaCount,
+/// packetDeltaCount, flowStartMilliseconds and flowEndMilliseconds.
+pub const IPFIX_COMMON_FIELDS: [(u16, u16); 7] = [(7, 2), (11, 2), (4, 1), (1, 8), (2, 8), (152, 8), (153, 8)];
+
+/// Returns the milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Sends IPFIX flow records for finished connections to a UDP collector.
+///
+/// Each message carries its own template set followed by two data records, one per
+/// direction, so a collector can decode it without having seen earlier messages.
+/// The proxy does not see IP packets, so the packet count is the number of reads forwarded.
+pub struct FlowExporter {
+    socket: Option<UdpSocket>,
+    domain_id: u32,
+    sequence: AtomicU32,
+}
+
+impl FlowExporter {
+    /// Creates an exporter sending to `collector`, or a disabled one if there is none.
+    pub async fn new(collector: Option<SocketAddr>, domain_id: u32) -> io::Result<Self> {
+        let socket: Option<UdpSocket> = match collector {
+            Some(collector) => {
+                let bind: SocketAddr = if collector.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+                let socket = UdpSocket::bind(bind).await?;
+                socket.connect(collector).await?;
+                Some(socket)
+            }
+            None => None,
+        };
+        Ok(FlowExporter { socket, domain_id, sequence: AtomicU32::new(0) })
+    }
+
+    /// Exports the two directions of a connection between `client` and `target`.
+    pub async fn export(&self, client: SocketAddr, target: SocketAddr, start_ms: u64, counters: &FlowCounters) -> io::Result<()> {
+        let Some(socket) = &self.socket else {
+            return Ok(());
+        };
+        let end_ms: u64 = unix_millis();
+
+        // Both records share one template, so mixed families are exported as IPv6.
+        let v4: bool = client.is_ipv4() && target.is_ipv4();
+        let (template, address_fields): (u16, [(u16, u16); 2]) = if v4 {
+            (IPFIX_TEMPLATE_V4, [(8, 4), (12, 4)])
+        } else {
+            (IPFIX_TEMPLATE_V6, [(27, 16), (28, 16)])
+        };
+        let put_ip = |out: &mut Vec<u8>, ip: IpAddr| match ip {
+            IpAddr::V4(ip) if v4 => out.extend_from_slice(&ip.octets()),
+            IpAddr::V4(ip) => out.extend_from_slice(&ip.to_ipv6_mapped().octets()),
+            IpAddr::V6(ip) => out.extend_from_slice(&ip.octets()),
+        };
+
+        // Template set.
+        let mut templates: Vec<u8> = Vec::new();
+        templates.extend_from_slice(&template.to_be_bytes());
+        templates.extend_from_slice(&((address_fields.len() + IPFIX_COMMON_FIELDS.len()) as u16).to_be_bytes());
+        for (id, length) in address_fields.iter().chain(IPFIX_COMMON_FIELDS.iter()) {
+            templates.extend_from_slice(&id.to_be_bytes());
+            templates.extend_from_slice(&length.to_be_bytes());
+        }
+
+        // Data set: client-to-target, then target-to-client.
+        let directions = [
+            (client, target, counters.up_bytes.load(Ordering::Relaxed), counters.up_packets.load(Ordering::Relaxed)),
+            (target, client, counters.down_bytes.load(Ordering::Relaxed), counters.down_packets.load(Ordering::Relaxed)),
+        ];
+        let mut records: Vec<u8> = Vec::new();
+        for (source, destination, bytes, packets) in directions {
+            put_ip(&mut records, source.ip());
+            put_ip(&mut records, destination.ip());
+            records.extend_from_slice(&source.port().to_be_bytes());
+            records.extend_from_slice(&destination.port().to_be_bytes());
+            records.push(libc::IPPROTO_TCP as u8);
+            records.extend_from_slice(&bytes.to_be_bytes());
+            records.extend_from_slice(&packets.to_be_bytes());
+            records.extend_from_slice(&start_ms.to_be_bytes());
+            records.extend_from_slice(&end_ms.to_be_bytes());
+        }
+
+        // The sequence number counts the data records sent before this message.
+        let sequence: u32 = self.sequence.fetch_add(directions.len() as u32, Ordering::Relaxed);
+        let length: usize = 16 + 4 + templates.len() + 4 + records.len();
+        let mut message: Vec<u8> = Vec::with_capacity(length);
+        message.extend_from_slice(&10u16.to_be_bytes());
+        message.extend_from_slice(&(length as u16).to_be_bytes());
+        message.extend_from_slice(&((end_ms / 1000) as u32).to_be_bytes());
+        message.extend_from_slice(&sequence.to_be_bytes());
+        message.extend_from_slice(&self.domain_id.to_be_bytes());
+        message.extend_from_slice(&2u16.to_be_bytes());
+        message.extend_from_slice(&((4 + templates.len()) as u16).to_be_bytes());
+        message.extend_from_slice(&templates);
+        message.extend_from_slice(&template.to_be_bytes());
+        message.extend_from_slice(&((4 + records.len()) as u16).to_be_bytes());
+        message.extend_from_slice(&records);
+
+        socket.send(&message).await?;
+        Ok(())
+    }
+}
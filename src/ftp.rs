@@ -0,0 +1,267 @@
+//! FTP support for `--mode ftp`: passive-mode replies are rewritten to point at relay
+//! ports on the proxy, which forward the data connections to the FTP server.
+
+use crate::config::Args;
+use crate::listener::{within_limits, ConnectionGuard, Stats};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+/// How long a relay port waits for the client's data connection.
+pub const FTP_RELAY_ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest control line kept while waiting for its end; longer lines are passed on unread.
+pub const FTP_MAX_LINE: usize = 8192;
+
+/// Returns the port announced in a `227` reply, such as `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2).`
+pub fn parse_pasv_port(line: &str) -> Option<u16> {
+    let rest: &str = line.strip_prefix("227")?;
+    let start: usize = rest.find(|c: char| c.is_ascii_digit())?;
+    let numbers: Vec<u8> = rest[start..]
+        .split(|c: char| !c.is_ascii_digit() && c != ',')
+        .next()?
+        .split(',')
+        .map(|n| n.parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    match numbers[..] {
+        [_, _, _, _, p1, p2] => Some(u16::from_be_bytes([p1, p2])).filter(|port| *port != 0),
+        _ => None,
+    }
+}
+
+/// Returns the port announced in a `229` reply, such as `229 Entering Extended Passive Mode (|||port|)`.
+pub fn parse_epsv_port(line: &str) -> Option<u16> {
+    let rest: &str = line.strip_prefix("229")?;
+    let open: usize = rest.find('(')?;
+    let inner: &str = &rest[open + 1..rest[open..].find(')')? + open];
+    // RFC 2428 allows any printable delimiter except a digit, which would be ambiguous with the port.
+    let delimiter: char = inner.chars().next().filter(|c| c.is_ascii_graphic() && !c.is_ascii_digit())?;
+    let fields: Vec<&str> = inner.split(delimiter).collect();
+    match fields[..] {
+        ["", "", "", port, ""] if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => port.parse().ok().filter(|port| *port != 0),
+        _ => None,
+    }
+}
+
+/// Listens on `proxy_ip` for one data connection from `client_ip` and relays it to `server`; returns the relay port.
+///
+/// Connections from any other address are closed while the relay keeps waiting, so a port
+/// scanner cannot take over someone else's transfer. The relayed connection counts against
+/// the connection limits like any other, and `ctl kill` can close it.
+pub async fn open_relay(proxy_ip: IpAddr, client_ip: IpAddr, server: SocketAddr, args: Arc<Args>, stats: Arc<Stats>) -> io::Result<u16> {
+    let listener: TcpListener = TcpListener::bind((proxy_ip, 0)).await?;
+    let port: u16 = listener.local_addr()?.port();
+
+    tokio::spawn(async move {
+        let deadline: Instant = Instant::now() + FTP_RELAY_ACCEPT_TIMEOUT;
+        let (mut client, client_addr) = loop {
+            let Ok(Ok((client, client_addr))) = tokio::time::timeout_at(deadline, listener.accept()).await else {
+                return;
+            };
+            if client_addr.ip().to_canonical() == client_ip.to_canonical() {
+                break (client, client_addr);
+            }
+            log!(Warn, "ftp_relay_foreign_peer", { "client" => client_addr, "port" => port }, "Closing FTP data connection from {} on relay port {}: it is not the control connection's client", client_addr, port);
+        };
+        drop(listener);
+        if !within_limits(&args, &stats, client_addr) {
+            return;
+        }
+        let (guard, stop) = ConnectionGuard::new(Arc::clone(&stats), client_addr);
+        let relay = async {
+            let mut upstream: TcpStream = TcpStream::connect(server).await?;
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+        };
+        tokio::select! {
+            result = relay => {
+                if let Err(e) = result {
+                    log!(Warn, "ftp_relay_failed", { "server" => server, "error" => e }, "FTP data connection to {} failed: {}", server, e);
+                }
+            }
+            _ = stop.notified() => {}
+        }
+        drop(guard);
+    });
+
+    Ok(port)
+}
+
+/// Rewrites the FTP server's passive-mode replies as they pass through to the client.
+///
+/// The server's address is replaced with the proxy's, and its port with a relay port
+/// opened for the purpose. The data connection always goes to the control connection's
+/// server address, which also fixes servers behind NAT that announce a private address.
+pub struct FtpRewriter {
+    proxy_ip: IpAddr,
+    client_ip: IpAddr,
+    server_ip: IpAddr,
+    args: Arc<Args>,
+    stats: Arc<Stats>,
+    pending: Vec<u8>,
+}
+
+impl FtpRewriter {
+    /// Creates a rewriter for a control connection from `client_ip` that reached the proxy at `proxy_ip` and leads to `server_ip`.
+    pub fn new(proxy_ip: IpAddr, client_ip: IpAddr, server_ip: IpAddr, args: Arc<Args>, stats: Arc<Stats>) -> Self {
+        FtpRewriter { proxy_ip, client_ip, server_ip, args, stats, pending: Vec::new() }
+    }
+
+    /// Takes the next chunk from the server and returns the complete lines it finishes, rewritten.
+    ///
+    /// A trailing partial line is kept until the rest of it arrives.
+    pub async fn rewrite(&mut self, data: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(data);
+        let end: usize = match self.pending.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => newline + 1,
+            None if self.pending.len() > FTP_MAX_LINE => self.pending.len(),
+            None => return Vec::new(),
+        };
+        let complete: Vec<u8> = self.pending.drain(..end).collect();
+
+        let mut output: Vec<u8> = Vec::with_capacity(complete.len());
+        for line in complete.split_inclusive(|&b| b == b'\n') {
+            // If no relay port can be opened, the reply goes through as is and the transfer fails on its own.
+            match self.rewrite_line(line).await {
+                Ok(Some(rewritten)) => output.extend_from_slice(rewritten.as_bytes()),
+                Ok(None) => output.extend_from_slice(line),
+                Err(e) => {
+                    log!(Warn, "ftp_relay_failed", { "error" => e }, "Failed to open an FTP data relay: {}", e);
+                    output.extend_from_slice(line);
+                }
+            }
+        }
+        output
+    }
+
+    /// Returns a partial line still waiting for its end, once the server has closed.
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Returns the replacement for a passive-mode reply, or `None` to pass `line` on unchanged.
+    async fn rewrite_line(&self, line: &[u8]) -> io::Result<Option<String>> {
+        let Ok(text) = std::str::from_utf8(line) else {
+            return Ok(None);
+        };
+        if let Some(port) = parse_pasv_port(text) {
+            // A 227 reply can only carry an IPv4 address.
+            let IpAddr::V4(proxy_ip) = self.proxy_ip else {
+                log!(Warn, "ftp_pasv_ipv6", {}, "Cannot rewrite a PASV reply for a client connected over IPv6; the client should use EPSV");
+                return Ok(None);
+            };
+            let relay: u16 = open_relay(self.proxy_ip, self.client_ip, SocketAddr::new(self.server_ip, port), Arc::clone(&self.args), Arc::clone(&self.stats)).await?;
+            let [a, b, c, d] = proxy_ip.octets();
+            let [p1, p2] = relay.to_be_bytes();
+            return Ok(Some(format!("227 Entering Passive Mode ({},{},{},{},{},{}).\r\n", a, b, c, d, p1, p2)));
+        }
+        if let Some(port) = parse_epsv_port(text) {
+            let relay: u16 = open_relay(self.proxy_ip, self.client_ip, SocketAddr::new(self.server_ip, port), Arc::clone(&self.args), Arc::clone(&self.stats)).await?;
+            return Ok(Some(format!("229 Entering Extended Passive Mode (|||{}|)\r\n", relay)));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{resolve_payloads, validate};
+    use crate::flow::FlowExporter;
+    use crate::session::{handle_client, DialLimiter};
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn ftp_clients_first_read_the_server_greeting() {
+        let server: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_port: String = server.local_addr().unwrap().port().to_string();
+        tokio::spawn(async move {
+            let (mut control, _) = server.accept().await.unwrap();
+            control.write_all(b"220 Welcome\r\n").await.unwrap();
+            let mut discard: [u8; 64] = [0; 64];
+            while control.read(&mut discard).await.unwrap_or(0) > 0 {}
+        });
+
+        let mut args: Args = Args::try_parse_from(["proxy-stream", "--mode", "ftp", "-H", "127.0.0.1", "-p", &server_port]).unwrap();
+        validate(&args).unwrap();
+        resolve_payloads(&mut args).unwrap();
+        let args: Arc<Args> = Arc::new(args);
+
+        let proxy: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr: SocketAddr = proxy.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (client, _) = proxy.accept().await.unwrap();
+            let flows: Arc<FlowExporter> = Arc::new(FlowExporter::new(None, 0).await.unwrap());
+            let _ = handle_client(client, args, Arc::new(DialLimiter::new(0)), Arc::new(Stats::default()), flows).await;
+        });
+
+        let mut client: TcpStream = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut greeting: Vec<u8> = vec![0; 13];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut greeting)).await.unwrap().unwrap();
+        assert_eq!(greeting, b"220 Welcome\r\n");
+    }
+
+    #[test]
+    fn pasv_replies_give_the_port() {
+        assert_eq!(parse_pasv_port("227 Entering Passive Mode (192,168,1,2,19,137)."), Some(5001));
+        assert_eq!(parse_pasv_port("227 Entering Passive Mode (10,0,0,1,255,255)"), Some(65535));
+        // Some servers leave out the parentheses, which RFC 1123 tells clients to tolerate.
+        assert_eq!(parse_pasv_port("227 =192,168,1,2,19,137"), Some(5001));
+        assert_eq!(parse_pasv_port("227 Entering Passive Mode 192,168,1,2,0,21"), Some(21));
+    }
+
+    #[test]
+    fn malformed_pasv_replies_are_rejected() {
+        for line in [
+            "227 Entering Passive Mode (192,168,1,256,19,137)",
+            "227 Entering Passive Mode (192,168,1,2,19,300)",
+            "227 Entering Passive Mode (192,168,1,2,19)",
+            "227 Entering Passive Mode (192,168,1,2,19,137,1)",
+            "227 Entering Passive Mode (192,168,,2,19,137)",
+            "227 Entering Passive Mode (192,168,1,2,0,0)",
+            "227 Entering Passive Mode",
+            "229 Entering Passive Mode (192,168,1,2,19,137)",
+            "",
+        ] {
+            assert_eq!(parse_pasv_port(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn epsv_replies_give_the_port() {
+        assert_eq!(parse_epsv_port("229 Entering Extended Passive Mode (|||6446|)"), Some(6446));
+        assert_eq!(parse_epsv_port("229 Entering Extended Passive Mode (!!!65535!)."), Some(65535));
+    }
+
+    #[test]
+    fn malformed_epsv_replies_are_rejected() {
+        for line in [
+            "229 Entering Extended Passive Mode |||6446|",
+            "229 Entering Extended Passive Mode (|||6446|",
+            "229 Entering Extended Passive Mode (|||6446#)",
+            "229 Entering Extended Passive Mode (#||6446|)",
+            "229 Entering Extended Passive Mode (1116461)",
+            "229 Entering Extended Passive Mode ( 6446 )",
+            "229 Entering Extended Passive Mode (|||+6446|)",
+            "229 Entering Extended Passive Mode (||||)",
+            "229 Entering Extended Passive Mode (|||0|)",
+            "229 Entering Extended Passive Mode (|||65536|)",
+            "229 Entering Extended Passive Mode (|1|2|3|)",
+            "227 Entering Extended Passive Mode (|||6446|)",
+        ] {
+            assert_eq!(parse_epsv_port(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn very_long_replies_are_handled() {
+        let padding: String = "x".repeat(1 << 20);
+        assert_eq!(parse_pasv_port(&format!("227 {} (192,168,1,2,19,137)", padding)), Some(5001));
+        assert_eq!(parse_epsv_port(&format!("229 {} (|||6446|)", padding)), Some(6446));
+        assert_eq!(parse_pasv_port(&format!("227 ({})", "1,".repeat(1 << 18))), None);
+        assert_eq!(parse_epsv_port(&format!("229 (|||{}|)", "9".repeat(1 << 18))), None);
+    }
+}
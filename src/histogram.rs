@@ -0,0 +1,158 @@
+//! Lock-free histograms for the per-connection latency, duration and size percentiles.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Values below this get a bucket each.
+const LINEAR_BUCKETS: usize = 16;
+
+/// Each power of two from `LINEAR_BUCKETS` up is split into this many buckets, which keeps
+/// a reported percentile within 25% of the true value.
+const SUB_BUCKETS: usize = 4;
+
+/// Enough buckets for any `u64`.
+const BUCKETS: usize = LINEAR_BUCKETS + (64 - 4) * SUB_BUCKETS;
+
+/// A histogram of `u64` values in log-linear buckets.
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram { buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect() }
+    }
+}
+
+/// Returns the bucket `value` falls into.
+fn bucket_of(value: u64) -> usize {
+    if value < LINEAR_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent: usize = 63 - value.leading_zeros() as usize;
+    let sub: usize = (value >> (exponent - 2)) as usize & (SUB_BUCKETS - 1);
+    LINEAR_BUCKETS + (exponent - 4) * SUB_BUCKETS + sub
+}
+
+/// Returns the largest value that falls into `bucket`.
+fn bucket_max(bucket: usize) -> u64 {
+    if bucket < LINEAR_BUCKETS {
+        return bucket as u64;
+    }
+    let exponent: usize = (bucket - LINEAR_BUCKETS) / SUB_BUCKETS + 4;
+    let sub: u64 = ((bucket - LINEAR_BUCKETS) % SUB_BUCKETS) as u64;
+    let width: u64 = 1 << (exponent - 2);
+    ((SUB_BUCKETS as u64 + sub) << (exponent - 2)).saturating_add(width - 1)
+}
+
+impl Histogram {
+    /// Adds `value` to the histogram.
+    pub fn record(&self, value: u64) {
+        self.buckets[bucket_of(value)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Returns an upper bound for the `percentile` (0 to 100) of the recorded values, or 0 if there are none.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let counts: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let rank: u64 = ((total as f64 * percentile / 100.0).ceil() as u64).clamp(1, total);
+        let mut seen: u64 = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_max(bucket);
+            }
+        }
+        bucket_max(BUCKETS - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values_get_a_bucket_each() {
+        for value in 0..LINEAR_BUCKETS as u64 {
+            assert_eq!(bucket_of(value), value as usize);
+            assert_eq!(bucket_max(bucket_of(value)), value);
+        }
+    }
+
+    #[test]
+    fn sub_buckets_split_at_their_boundaries() {
+        assert_eq!(bucket_of(15), 15);
+        assert_eq!(bucket_of(16), 16);
+        assert_eq!(bucket_of(19), 16);
+        assert_eq!(bucket_of(20), 17);
+        assert_eq!(bucket_max(16), 19);
+        assert_eq!(bucket_max(17), 23);
+        assert_eq!(bucket_of(31), 19);
+        assert_eq!(bucket_of(32), 20);
+        assert_eq!(bucket_max(20), 39);
+    }
+
+    #[test]
+    fn the_extremes_have_buckets() {
+        assert_eq!(bucket_of(0), 0);
+        assert_eq!(bucket_max(0), 0);
+        assert_eq!(bucket_of(u64::MAX), BUCKETS - 1);
+        assert_eq!(bucket_max(BUCKETS - 1), u64::MAX);
+        let histogram: Histogram = Histogram::default();
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.percentile(50.0), u64::MAX);
+    }
+
+    #[test]
+    fn every_value_is_at_most_its_bucket_max() {
+        let mut value: u64 = 1;
+        while value < u64::MAX / 3 {
+            for probe in [value - 1, value, value + 1] {
+                let bucket: usize = bucket_of(probe);
+                assert!(probe <= bucket_max(bucket), "{} exceeds bucket {}", probe, bucket);
+                assert!(bucket == 0 || probe > bucket_max(bucket - 1), "{} belongs below bucket {}", probe, bucket);
+            }
+            value = value * 3 / 2 + 1;
+        }
+    }
+
+    #[test]
+    fn bucket_max_is_strictly_increasing() {
+        for bucket in 1..BUCKETS {
+            assert!(bucket_max(bucket) > bucket_max(bucket - 1), "bucket {}", bucket);
+        }
+    }
+
+    #[test]
+    fn percentiles_of_empty_and_single_sample_histograms() {
+        let histogram: Histogram = Histogram::default();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.percentile(0.0), 0);
+        assert_eq!(histogram.percentile(99.0), 0);
+
+        histogram.record(100);
+        assert_eq!(histogram.count(), 1);
+        for percentile in [0.0, 50.0, 99.0, 100.0] {
+            assert_eq!(histogram.percentile(percentile), bucket_max(bucket_of(100)));
+        }
+        assert!(bucket_max(bucket_of(100)) >= 100 && bucket_max(bucket_of(100)) < 125);
+    }
+
+    #[test]
+    fn percentiles_rank_the_recorded_values() {
+        let histogram: Histogram = Histogram::default();
+        for value in 1..=100 {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.percentile(10.0), 10);
+        assert_eq!(histogram.percentile(50.0), bucket_max(bucket_of(50)));
+        assert_eq!(histogram.percentile(100.0), bucket_max(bucket_of(100)));
+    }
+}
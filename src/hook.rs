@@ -0,0 +1,46 @@
+//! External commands run on connection events, for `--on-connect` and `--on-close`.
+
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::process::Command;
+
+/// How long a hook may run before it is killed.
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs `command` through the shell in the background with `env` added to its environment.
+///
+/// The connection does not wait for the hook; failures, non-zero exits and hooks that run
+/// past `HOOK_TIMEOUT` are logged.
+pub fn run_hook(event: &'static str, command: &str, env: Vec<(&'static str, String)>) {
+    #[cfg(unix)]
+    let mut child: Command = {
+        let mut child: Command = Command::new("sh");
+        child.arg("-c").arg(command);
+        child
+    };
+    #[cfg(not(unix))]
+    let mut child: Command = {
+        let mut child: Command = Command::new("cmd");
+        child.arg("/C").arg(command);
+        child
+    };
+    child.env("PROXY_STREAM_EVENT", event).envs(env).stdin(Stdio::null()).kill_on_drop(true);
+
+    tokio::spawn(async move {
+        let status: std::io::Result<ExitStatus> = match child.spawn() {
+            Ok(mut child) => match tokio::time::timeout(HOOK_TIMEOUT, child.wait()).await {
+                Ok(status) => status,
+                Err(_) => {
+                    log!(Warn, "hook_timeout", { "event" => event, "timeout" => HOOK_TIMEOUT.as_secs() }, "The {} hook ran for more than {} seconds and was killed", event, HOOK_TIMEOUT.as_secs());
+                    return;
+                }
+            },
+            Err(e) => Err(e),
+        };
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => log!(Warn, "hook_failed", { "event" => event, "status" => status.to_string() }, "The {} hook exited with {}", event, status),
+            Err(e) => log!(Warn, "hook_failed", { "event" => event, "error" => e }, "Failed to run the {} hook: {}", event, e),
+        }
+    });
+}
@@ -0,0 +1,168 @@
+//! Port knocking for `--knock`: a client address must connect to a sequence of ports before
+//! the main listener serves it.
+
+use crate::listener::bind_listener;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+
+/// How long a client may take between two knocks before it has to start the sequence again.
+pub const KNOCK_STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tracks each address's progress through the knock sequence and which addresses have completed it.
+pub struct KnockGuard {
+    listen_addr: IpAddr,
+    ports: Vec<u16>,
+    window: Duration,
+    /// How many knocks each address has got right so far, and when it last knocked.
+    progress: Mutex<HashMap<IpAddr, (usize, Instant)>>,
+    /// Addresses that completed the sequence, and until when they may connect.
+    open: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl KnockGuard {
+    /// Creates a guard for the knock sequence `ports`, listened for on `listen_addr`, that admits an address for `window` after it completes.
+    pub fn new(listen_addr: IpAddr, ports: Vec<u16>, window: Duration) -> Self {
+        KnockGuard { listen_addr, ports, window, progress: Mutex::new(HashMap::new()), open: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns `true` if `ip` completed the knock sequence within the window.
+    pub fn is_open(&self, ip: IpAddr) -> bool {
+        let mut open = self.open.lock().unwrap();
+        let now: Instant = Instant::now();
+        open.retain(|_, until| *until > now);
+        open.contains_key(&ip)
+    }
+
+    /// Records a knock from `ip` on `port`, returning `true` if it completed the sequence.
+    ///
+    /// A knock on the wrong port starts the sequence over, counting it as the first knock
+    /// if it is on the first port.
+    pub fn knock(&self, ip: IpAddr, port: u16) -> bool {
+        let mut progress = self.progress.lock().unwrap();
+        let now: Instant = Instant::now();
+        progress.retain(|_, (_, at)| now.duration_since(*at) < KNOCK_STEP_TIMEOUT);
+
+        let done: usize = progress.get(&ip).map_or(0, |(done, _)| *done);
+        let done: usize = if self.ports[done] == port {
+            done + 1
+        } else if self.ports[0] == port {
+            1
+        } else {
+            progress.remove(&ip);
+            return false;
+        };
+        if done < self.ports.len() {
+            progress.insert(ip, (done, now));
+            return false;
+        }
+        progress.remove(&ip);
+        self.open.lock().unwrap().insert(ip, now + self.window);
+        true
+    }
+
+    /// Binds a listener on each knock port and records the connections they accept.
+    ///
+    /// Binding happens up front so a port that is already taken stops startup; knock
+    /// connections are closed as soon as they are accepted.
+    pub fn serve(self: &Arc<Self>) -> io::Result<()> {
+        let mut ports: Vec<u16> = self.ports.clone();
+        ports.sort_unstable();
+        ports.dedup();
+        for port in ports {
+            let listener: TcpListener = bind_listener(self.listen_addr, port, None, false)?;
+            let guard: Arc<KnockGuard> = Arc::clone(self);
+            tokio::spawn(async move {
+                loop {
+                    let client_addr: SocketAddr = match listener.accept().await {
+                        Ok((_, client_addr)) => client_addr,
+                        Err(e) => {
+                            log!(Warn, "knock_accept_failed", { "port" => port, "error" => e }, "Failed to accept a knock on port {}: {}", port, e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    };
+                    if guard.knock(client_addr.ip(), port) {
+                        log!(Info, "knock_completed", { "client" => client_addr, "window" => guard.window.as_secs() }, "{} completed the knock sequence and may connect for {} seconds", client_addr.ip(), guard.window.as_secs());
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    fn guard() -> KnockGuard {
+        KnockGuard::new(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), vec![7000, 8000, 9000], Duration::from_secs(30))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_right_sequence_opens_the_listener() {
+        let guard: KnockGuard = guard();
+        assert!(!guard.knock(CLIENT, 7000));
+        assert!(!guard.knock(CLIENT, 8000));
+        assert!(!guard.is_open(CLIENT));
+        assert!(guard.knock(CLIENT, 9000));
+        assert!(guard.is_open(CLIENT));
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(!guard.is_open(CLIENT));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn knocks_out_of_order_start_over() {
+        let guard: KnockGuard = guard();
+        for port in [8000, 7000, 9000, 8000, 9000] {
+            assert!(!guard.knock(CLIENT, port), "{}", port);
+        }
+        assert!(!guard.is_open(CLIENT));
+
+        // A wrong knock on the first port counts as the start of a new attempt.
+        for port in [7000, 7000, 8000] {
+            assert!(!guard.knock(CLIENT, port));
+        }
+        assert!(guard.knock(CLIENT, 9000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_slow_knock_starts_over() {
+        let guard: KnockGuard = guard();
+        assert!(!guard.knock(CLIENT, 7000));
+        tokio::time::advance(KNOCK_STEP_TIMEOUT - Duration::from_millis(1)).await;
+        assert!(!guard.knock(CLIENT, 8000));
+        tokio::time::advance(KNOCK_STEP_TIMEOUT).await;
+        assert!(!guard.knock(CLIENT, 9000));
+        assert!(!guard.is_open(CLIENT));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_knock_independently() {
+        let guard: KnockGuard = guard();
+        assert!(!guard.knock(CLIENT, 7000));
+        assert!(!guard.knock(OTHER, 7000));
+        assert!(!guard.knock(CLIENT, 8000));
+        // The other client's wrong knock does not reset this one's progress.
+        assert!(!guard.knock(OTHER, 9000));
+        assert!(guard.knock(CLIENT, 9000));
+        assert!(guard.is_open(CLIENT));
+        assert!(!guard.is_open(OTHER));
+
+        // Nor does another client's completed sequence open the listener for anyone else.
+        assert!(!guard.knock(OTHER, 7000));
+        assert!(!guard.knock(OTHER, 8000));
+        assert!(guard.knock(OTHER, 9000));
+        assert!(guard.is_open(OTHER));
+        assert!(!guard.is_open(IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 3))));
+    }
+}
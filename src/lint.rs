@@ -0,0 +1,111 @@
+//! The `payload lint` subcommand, which checks a payload before it is deployed.
+
+use crate::config::{unescape, LintArgs};
+
+/// Formats `bytes` with the escapes `--payload` accepts, starting a new line after each `\n`.
+pub fn escape_payload(bytes: &[u8]) -> String {
+    let mut output: String = String::with_capacity(bytes.len());
+    for &b in bytes {
+        match b {
+            b'\r' => output.push_str("\\r"),
+            b'\n' => output.push_str("\\n\n"),
+            b'\t' => output.push_str("\\t"),
+            b'\\' => output.push_str("\\\\"),
+            b' '..=b'~' => output.push(b as char),
+            _ => output.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    output
+}
+
+/// Returns `true` if `b` may appear in an HTTP header name.
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Checks the line endings and, for HTTP payloads, the status line and header syntax.
+///
+/// Returns one message per problem found; an empty list means the payload looks fine.
+pub fn check_payload(payload: &[u8]) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+
+    // Every line break must be a full CRLF.
+    let mut line: usize = 1;
+    for (i, &b) in payload.iter().enumerate() {
+        match b {
+            b'\n' if i == 0 || payload[i - 1] != b'\r' => problems.push(format!("line {}: bare \\n, expected \\r\\n", line)),
+            b'\r' if payload.get(i + 1) != Some(&b'\n') => problems.push(format!("line {}: bare \\r, expected \\r\\n", line)),
+            _ => {}
+        }
+        if b == b'\n' {
+            line += 1;
+        }
+    }
+
+    // Anything that is not an HTTP response is sent as is and has no further structure to check.
+    if !payload.starts_with(b"HTTP/") {
+        return problems;
+    }
+
+    let (head, complete): (&[u8], bool) = match payload.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => (&payload[..end], true),
+        None => (payload, false),
+    };
+    let mut lines = head.split(|&b| b == b'\n').map(|l| l.strip_suffix(b"\r").unwrap_or(l));
+
+    let status: String = String::from_utf8_lossy(lines.next().unwrap_or_default()).into_owned();
+    let mut parts = status.splitn(3, ' ');
+    let version: &str = parts.next().unwrap_or_default();
+    let code: &str = parts.next().unwrap_or_default();
+    if !matches!(version, "HTTP/1.0" | "HTTP/1.1") {
+        problems.push(format!("line 1: unknown HTTP version {:?}", version));
+    }
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        problems.push(format!("line 1: status code {:?} is not three digits", code));
+    }
+
+    for (index, header) in lines.enumerate() {
+        let number: usize = index + 2;
+        if header.is_empty() {
+            continue;
+        }
+        let Some(colon) = header.iter().position(|&b| b == b':') else {
+            problems.push(format!("line {}: header has no ':'", number));
+            continue;
+        };
+        let name: &[u8] = &header[..colon];
+        if name.is_empty() {
+            problems.push(format!("line {}: header name is empty", number));
+        } else if !name.iter().all(|&b| is_token_byte(b)) {
+            problems.push(format!("line {}: header name {:?} contains invalid characters", number, String::from_utf8_lossy(name)));
+        }
+        if header[colon + 1..].iter().any(|&b| b.is_ascii_control() && b != b'\t') {
+            problems.push(format!("line {}: header value contains control characters", number));
+        }
+    }
+
+    if !complete {
+        problems.push("the headers are not terminated by an empty line (\\r\\n\\r\\n)".to_string());
+    }
+
+    problems
+}
+
+/// Runs `payload lint`: prints the bytes the payload file expands to and any problems with them.
+///
+/// Returns `Ok(false)` if problems were found.
+pub fn lint_payload(args: &LintArgs) -> Result<bool, Box<dyn std::error::Error>> {
+    // Match what `--payload "$(cat FILE)"` would pass: the shell drops trailing newlines.
+    let text: String = std::fs::read_to_string(&args.file)?;
+    let payload: Vec<u8> = unescape(text.trim_end_matches('\n'))?;
+
+    let escaped: String = escape_payload(&payload);
+    println!("{}", escaped.strip_suffix('\n').unwrap_or(&escaped));
+    log!(Info, "payload_size", { "bytes" => payload.len() }, "Payload is {} bytes", payload.len());
+
+    let problems: Vec<String> = check_payload(&payload);
+    for problem in &problems {
+        log!(Warn, "payload_problem", { "problem" => problem.as_str() }, "{}", problem);
+    }
+    Ok(problems.is_empty())
+}
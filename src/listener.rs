@@ -0,0 +1,590 @@
+//! The accept loop and the process-wide state it shares with connections.
+
+use crate::config::{Args, Mode};
+use crate::discovery::{advertise_mdns, maintain_nat_pmp_mapping, stun_public_address};
+use crate::control::{dump_stats, serve_control};
+use crate::flow::{unix_millis, FlowExporter};
+use crate::histogram::Histogram;
+use crate::knock::KnockGuard;
+use crate::session::{handle_client, set_mss, CloseReason, DialLimiter};
+use crate::upnp::maintain_upnp_mapping;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{Notify, Semaphore};
+use tokio::time::Instant;
+
+/// Process-wide counters shared between the accept loop and connection tasks.
+#[derive(Default)]
+pub struct Stats {
+    /// The number of `accept()` calls that failed with a recoverable error.
+    pub accept_errors: AtomicU64,
+
+    /// The number of client connections currently being handled.
+    pub active_connections: AtomicU64,
+
+    /// The soft open file limit set at startup, or 0 if it could not be read.
+    pub nofile_limit: AtomicU64,
+
+    /// The number of HTTP/2 streams opened by clients, with `--h2-metrics`.
+    pub h2_streams: AtomicU64,
+
+    /// The number of HTTP/2 streams reset by either side, with `--h2-metrics`.
+    pub h2_resets: AtomicU64,
+
+    /// The live connections by id, for the control socket.
+    pub connections: Mutex<HashMap<u64, LiveConnection>>,
+
+    /// The id handed to the next connection.
+    pub next_connection_id: AtomicU64,
+
+    /// The number of live connections from each client IP.
+    pub connections_per_ip: Mutex<HashMap<IpAddr, u64>>,
+
+    /// The number of lookups and failed lookups per resolver, by resolver name.
+    pub resolvers: Mutex<BTreeMap<String, (u64, u64)>>,
+
+    /// The target's addresses and when they expire, with `--dns-ttl-override`.
+    pub target_cache: Mutex<Option<(Instant, Vec<SocketAddr>)>>,
+
+    /// Target addresses that sent a banner not matching `--expect-banner`, and until when they are tried last.
+    pub unhealthy: Mutex<HashMap<SocketAddr, Instant>>,
+
+    /// How long dialing the target took, in milliseconds, for each connection that reached it.
+    pub connect_ms: Histogram,
+
+    /// How long each forwarded connection lasted, in milliseconds.
+    pub duration_ms: Histogram,
+
+    /// The bytes each forwarded connection carried, both directions together.
+    pub transfer_bytes: Histogram,
+
+    /// The average throughput of each forwarded connection, in bytes per second.
+    pub throughput_bps: Histogram,
+
+    /// The number of live connections to each target address.
+    pub targets: Mutex<HashMap<SocketAddr, u64>>,
+
+    /// Target addresses that new connections must avoid, set through the control socket.
+    pub draining: Mutex<HashSet<SocketAddr>>,
+}
+
+impl Stats {
+    /// Returns `true` if `addr` recently failed the banner check.
+    pub fn is_unhealthy(&self, addr: &SocketAddr) -> bool {
+        let mut unhealthy = self.unhealthy.lock().unwrap();
+        match unhealthy.get(addr) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                unhealthy.remove(addr);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Counts a lookup through `resolver`, treating an empty result as a failure, and passes the result on.
+    pub fn count_resolution(&self, resolver: &str, result: io::Result<Vec<SocketAddr>>) -> io::Result<Vec<SocketAddr>> {
+        let result: io::Result<Vec<SocketAddr>> = match result {
+            Ok(addrs) if addrs.is_empty() => Err(io::Error::new(io::ErrorKind::NotFound, "no addresses found")),
+            result => result,
+        };
+        let mut resolvers = self.resolvers.lock().unwrap();
+        let (queries, failures) = resolvers.entry(resolver.to_string()).or_default();
+        *queries += 1;
+        if result.is_err() {
+            *failures += 1;
+        }
+        result
+    }
+}
+
+/// What the control socket knows about a live connection.
+pub struct LiveConnection {
+    pub client: SocketAddr,
+    pub started_ms: u64,
+
+    /// Notified to close the connection.
+    pub stop: Arc<Notify>,
+}
+
+/// Tracks a live connection in `Stats` for as long as it is held.
+pub struct ConnectionGuard {
+    stats: Arc<Stats>,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    /// Registers a new active connection from `client` and returns the guard with the signal that stops it.
+    pub fn new(stats: Arc<Stats>, client: SocketAddr) -> (Self, Arc<Notify>) {
+        stats.active_connections.fetch_add(1, Ordering::Relaxed);
+        let id: u64 = stats.next_connection_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let stop: Arc<Notify> = Arc::new(Notify::new());
+        let live: LiveConnection = LiveConnection { client, started_ms: unix_millis(), stop: Arc::clone(&stop) };
+        stats.connections.lock().unwrap().insert(id, live);
+        *stats.connections_per_ip.lock().unwrap().entry(client.ip()).or_insert(0) += 1;
+        (ConnectionGuard { stats, id }, stop)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(live) = self.stats.connections.lock().unwrap().remove(&self.id) {
+            let mut per_ip = self.stats.connections_per_ip.lock().unwrap();
+            if let Some(count) = per_ip.get_mut(&live.client.ip()) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(&live.client.ip());
+                }
+            }
+        }
+        self.stats.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How long a refused client gets to read the busy response before its socket is dropped.
+const BUSY_RESPONSE_LINGER: Duration = Duration::from_secs(1);
+
+/// How many refused clients may linger over their busy response at once.
+const MAX_LINGERING_BUSY_RESPONSES: usize = 256;
+
+/// Sends `response` to a client refused for load, then closes the connection in the background.
+///
+/// The client's unread request is drained for a moment first: closing a socket with unread
+/// data resets it, which can throw away the response before the client has read it. Each
+/// lingering client holds a permit from `lingering`; when they are all taken, the response
+/// only gets what fits in the socket buffer right away and the connection is closed at once,
+/// so a flood of refused clients cannot pile up tasks and descriptors.
+fn send_busy_response(mut client: TcpStream, response: &[u8], lingering: &Arc<Semaphore>) {
+    if response.is_empty() {
+        return;
+    }
+    let Ok(permit) = Arc::clone(lingering).try_acquire_owned() else {
+        let _ = client.try_write(response);
+        return;
+    };
+    let response: Vec<u8> = response.to_vec();
+    tokio::spawn(async move {
+        let _permit = permit;
+        let _ = tokio::time::timeout(BUSY_RESPONSE_LINGER, async {
+            client.write_all(&response).await?;
+            client.shutdown().await?;
+            let mut discard: [u8; 1024] = [0; 1024];
+            while client.read(&mut discard).await? > 0 {}
+            Ok::<(), io::Error>(())
+        })
+        .await;
+    });
+}
+
+/// Returns the unspecified address of `ip`'s family.
+fn wildcard(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Completes at the next tick of `interval`, or never if there is none.
+async fn next_tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Tries to bind the primary listen address while the backup keeps serving, logging why not if it fails.
+///
+/// Both listeners share the port (see `bind_listener`), so the backup only has to be closed
+/// once the primary is up.
+fn retry_primary(args: &Args) -> Option<TcpListener> {
+    match bind_listener(args.listen_addr, args.listen_port, args.mss_clamp, true) {
+        Ok(primary) => Some(primary),
+        Err(e) => {
+            log!(Warn, "listen_primary_failed", { "listen_addr" => args.listen_addr, "error" => e }, "Still unable to listen on primary address {}: {}", args.listen_addr, e);
+            None
+        }
+    }
+}
+
+/// Closes a listener that is being replaced, returning the connections still waiting in its
+/// accept queue so they are served instead of reset.
+fn drain_accept_queue(listener: TcpListener) -> Vec<(TcpStream, SocketAddr)> {
+    let mut queued: Vec<(TcpStream, SocketAddr)> = Vec::new();
+    let Ok(listener) = listener.into_std() else {
+        return queued;
+    };
+    while let Ok((client, client_addr)) = listener.accept() {
+        match client.set_nonblocking(true).and_then(|()| TcpStream::from_std(client)) {
+            Ok(client) => queued.push((client, client_addr)),
+            Err(e) => log!(Warn, "listen_handover_failed", { "client" => client_addr, "error" => e }, "Failed to take over {} from the backup address: {}", client_addr, e),
+        }
+    }
+    queued
+}
+
+/// Returns `true` if another connection from `client_addr` fits within `--max-connections`
+/// and `--max-connections-per-ip`, logging why not otherwise.
+pub fn within_limits(args: &Args, stats: &Stats, client_addr: SocketAddr) -> bool {
+    // At the connection limit, refuse new clients; the last reserved slots are kept for
+    // allowlisted clients so operators can still reach their own tunnel during a flood.
+    if args.max_connections > 0 {
+        let active: u64 = stats.active_connections.load(Ordering::Relaxed);
+        let reserved: bool = args.reserve_allow.iter().any(|cidr| cidr.contains(client_addr.ip()));
+        let limit: u64 = if reserved { args.max_connections } else { args.max_connections.saturating_sub(args.reserved_connections) };
+        if active >= limit {
+            log!(
+                Warn,
+                "connection_limit",
+                { "client" => client_addr, "active" => active, "limit" => limit },
+                "Refusing {}:{}: {} of {} connection slots in use",
+                client_addr.ip(),
+                client_addr.port(),
+                active,
+                limit
+            );
+            return false;
+        }
+    }
+    // A single address may only hold so many of the slots, so one host cannot starve the rest.
+    if args.max_connections_per_ip > 0 {
+        let active: u64 = stats.connections_per_ip.lock().unwrap().get(&client_addr.ip()).copied().unwrap_or(0);
+        if active >= args.max_connections_per_ip {
+            log!(
+                Warn,
+                "connection_limit_per_ip",
+                { "client" => client_addr, "active" => active, "limit" => args.max_connections_per_ip },
+                "Refusing {}:{}: {} connections from this address already active (limit {})",
+                client_addr.ip(),
+                client_addr.port(),
+                active,
+                args.max_connections_per_ip
+            );
+            return false;
+        }
+    }
+    true
+}
+
+/// Counts a live connection to a target address in `Stats` for as long as it is held.
+pub struct TargetGuard {
+    stats: Arc<Stats>,
+    addr: SocketAddr,
+}
+
+impl TargetGuard {
+    /// Registers a new connection to the target address `addr`.
+    pub fn new(stats: Arc<Stats>, addr: SocketAddr) -> Self {
+        *stats.targets.lock().unwrap().entry(addr).or_insert(0) += 1;
+        TargetGuard { stats, addr }
+    }
+}
+
+impl Drop for TargetGuard {
+    fn drop(&mut self) {
+        let mut targets = self.stats.targets.lock().unwrap();
+        if let Some(count) = targets.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                targets.remove(&self.addr);
+                if self.stats.draining.lock().unwrap().contains(&self.addr) {
+                    log!(Info, "target_drained", { "addr" => self.addr }, "Target {} is drained: no connections left", self.addr);
+                }
+            }
+        }
+    }
+}
+
+/// Binds a listening socket on `ip`, clamping the MSS it advertises if `mss` is set.
+///
+/// With `shared`, the port is bound with `SO_REUSEPORT` so a listener on another address can
+/// hold it at the same time, even when one of the addresses is a wildcard.
+pub fn bind_listener(ip: IpAddr, port: u16, mss: Option<u16>, shared: bool) -> io::Result<TcpListener> {
+    let socket: TcpSocket = if ip.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    if shared {
+        socket.set_reuseport(true)?;
+    }
+    #[cfg(not(unix))]
+    let _ = shared;
+    if let Some(mss) = mss {
+        set_mss(&socket, mss)?;
+    }
+    socket.bind((ip, port).into())?;
+    socket.listen(1024)
+}
+
+/// Raises the soft `RLIMIT_NOFILE` limit to `target` (or the hard limit if unset) and returns the new soft limit.
+///
+/// The target is capped at the hard limit, and the soft limit is never lowered.
+#[cfg(unix)]
+pub fn raise_nofile_limit(target: Option<u64>) -> io::Result<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: `limit` is a valid, writable `rlimit` for the duration of the call.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let wanted: libc::rlim_t = target.map_or(limit.rlim_max, |t| t as libc::rlim_t).min(limit.rlim_max);
+    if wanted > limit.rlim_cur {
+        let raised = libc::rlimit { rlim_cur: wanted, rlim_max: limit.rlim_max };
+        // SAFETY: `raised` is a valid `rlimit` for the duration of the call.
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        limit.rlim_cur = wanted;
+    }
+
+    // `rlim_t` is only 32 bits wide on some targets, so the conversion is not always a no-op.
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(limit.rlim_cur))
+}
+
+/// Open file limits are not managed on this platform.
+#[cfg(not(unix))]
+pub fn raise_nofile_limit(_target: Option<u64>) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "RLIMIT_NOFILE is not available on this platform"))
+}
+
+/// Returns `true` if an `accept()` error is transient and the listener can keep going.
+///
+/// These are resource exhaustion conditions (out of file descriptors or buffers) and
+/// connections that were aborted by the peer before they could be accepted.
+pub fn is_transient_accept_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => true,
+        _ => matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)),
+    }
+}
+
+/// Runs the server.
+///
+/// This function binds the listener to the specified listen port and enters an
+/// infinite loop where it accepts and handles incoming connections.
+pub async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    // Wrap the arguments in an `Arc` for shared ownership across tasks.
+    let args: Arc<Args> = Arc::new(args);
+    let dials: Arc<DialLimiter> = Arc::new(DialLimiter::new(args.max_upstream_dials));
+    let stats: Arc<Stats> = Arc::new(Stats::default());
+    let flows: Arc<FlowExporter> = Arc::new(FlowExporter::new(args.flow_collector, args.flow_domain_id).await?);
+
+    // Print startup information.
+    log!(Info, "server_started", { "listen_port" => args.listen_port }, "Server started on port: {}", args.listen_port);
+    if args.mode == Mode::Echo {
+        log!(Info, "echo_mode", {}, "Echo mode: client data is sent back, no target is dialed");
+    } else {
+        log!(Info, "target", { "target_host" => args.target_host, "target_port" => args.target_port }, "Redirecting requests to: {} at port {}", args.target_host, args.target_port);
+        if let Some(socks) = args.upstream_socks5 {
+            log!(Info, "upstream_socks5", { "socks_server" => socks }, "Connecting through SOCKS5 server {}, which resolves the target", socks);
+        }
+    }
+
+    // Each proxied connection holds two sockets, so make as many file descriptors available as we can.
+    let fd_limit: Option<u64> = match raise_nofile_limit(args.nofile_limit) {
+        Ok(limit) => {
+            log!(Info, "nofile_limit", { "limit" => limit, "connections" => limit / 2 }, "Open file limit: {} (room for about {} connections)", limit, limit / 2);
+            stats.nofile_limit.store(limit, Ordering::Relaxed);
+            Some(limit)
+        }
+        Err(e) => {
+            log!(Warn, "nofile_limit_failed", { "error" => e }, "Failed to adjust open file limit: {}", e);
+            None
+        }
+    };
+    let mut fd_warned: bool = false;
+    let lingering: Arc<Semaphore> = Arc::new(Semaphore::new(MAX_LINGERING_BUSY_RESPONSES));
+
+    // Public address discovery only informs the operator, so it must not hold up startup.
+    if let Some(server) = args.stun_server.clone() {
+        let listen_port: u16 = args.listen_port;
+        tokio::spawn(async move {
+            match stun_public_address(&server).await {
+                Ok(public) => log!(
+                    Info,
+                    "stun_public_address",
+                    { "stun_server" => server, "public_ip" => public.ip().to_string(), "listen_port" => listen_port },
+                    "Public address (via STUN {}): {} (clients behind NAT should connect to {}:{} if port {} is forwarded)",
+                    server,
+                    public.ip(),
+                    public.ip(),
+                    listen_port,
+                    listen_port
+                ),
+                Err(e) => log!(Warn, "stun_failed", { "stun_server" => server, "error" => e }, "Failed to query STUN server {}: {}", server, e),
+            }
+        });
+    }
+
+    // Port mapping is best effort as well.
+    if args.nat_pmp {
+        let args: Arc<Args> = Arc::clone(&args);
+        tokio::spawn(async move {
+            if let Err(e) = maintain_nat_pmp_mapping(args).await {
+                log!(Warn, "nat_pmp_failed", { "error" => e }, "NAT-PMP port mapping stopped: {}", e);
+            }
+        });
+    }
+    if args.upnp {
+        let args: Arc<Args> = Arc::clone(&args);
+        tokio::spawn(async move {
+            if let Err(e) = maintain_upnp_mapping(args).await {
+                log!(Warn, "upnp_failed", { "error" => e }, "UPnP port mapping stopped: {}", e);
+            }
+        });
+    }
+
+    // Advertising is best effort: a failure is logged and the server keeps running.
+    if args.mdns {
+        let args: Arc<Args> = Arc::clone(&args);
+        tokio::spawn(async move {
+            if let Err(e) = advertise_mdns(args).await {
+                log!(Warn, "mdns_failed", { "error" => e }, "mDNS advertisement stopped: {}", e);
+            }
+        });
+    }
+
+    // The control socket is for the operator's scripts; losing it must not stop the proxy.
+    if let Some(path) = args.control_socket.clone() {
+        let args: Arc<Args> = Arc::clone(&args);
+        let stats: Arc<Stats> = Arc::clone(&stats);
+        tokio::spawn(async move {
+            if let Err(e) = serve_control(&path, args, stats).await {
+                log!(Warn, "control_socket_failed", { "error" => e }, "Control socket stopped: {}", e);
+            }
+        });
+    }
+
+    // SIGUSR1 dumps the stats, for operators who only have a shell.
+    #[cfg(unix)]
+    {
+        let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+        let args: Arc<Args> = Arc::clone(&args);
+        let stats: Arc<Stats> = Arc::clone(&stats);
+        tokio::spawn(async move {
+            while signal.recv().await.is_some() {
+                dump_stats(&args, &stats);
+            }
+        });
+    }
+
+    // With port knocking, the knock ports must be listening before any client can get in.
+    // They listen on every address, so they work whichever of the listen addresses is bound.
+    let knocks: Option<Arc<KnockGuard>> = (!args.knock_ports.is_empty()).then(|| Arc::new(KnockGuard::new(wildcard(args.listen_addr), args.knock_ports.clone(), Duration::from_secs(args.knock_window))));
+    if let Some(knocks) = &knocks {
+        knocks.serve()?;
+    }
+
+    // Bind the listener to the specified `listen_port` to accept incoming TCP connections.
+    // If the primary address is not available yet, serve from the backup and keep retrying it.
+    let shared: bool = args.backup_listen_addr.is_some();
+    let (mut listener, mut primary_retry): (TcpListener, Option<tokio::time::Interval>) = match bind_listener(args.listen_addr, args.listen_port, args.mss_clamp, shared) {
+        Ok(listener) => (listener, None),
+        Err(e) => {
+            let Some(backup) = args.backup_listen_addr else {
+                return Err(e.into());
+            };
+            let listener: TcpListener = bind_listener(backup, args.listen_port, args.mss_clamp, true)?;
+            log!(
+                Warn,
+                "listen_backup",
+                { "listen_addr" => args.listen_addr, "backup_listen_addr" => backup, "error" => e },
+                "Failed to listen on {}: {}; listening on backup address {} instead",
+                args.listen_addr,
+                e,
+                backup
+            );
+            let period: Duration = Duration::from_secs(args.primary_retry_interval);
+            (listener, Some(tokio::time::interval_at(Instant::now() + period, period)))
+        }
+    };
+
+    let mut handed_over: Vec<(TcpStream, SocketAddr)> = Vec::new();
+
+    // Enter an infinite loop to accept incoming connections.
+    loop {
+        // Accept a new client connection.
+        // Transient failures such as running out of file descriptors must not take the whole
+        // server down, so log them, back off briefly and keep accepting.
+        // Connections handed over from a closed backup listener come first.
+        let accepted: io::Result<(TcpStream, SocketAddr)> = match handed_over.pop() {
+            Some(accepted) => Ok(accepted),
+            None => tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = next_tick(&mut primary_retry) => {
+                    if let Some(primary) = retry_primary(&args) {
+                        handed_over = drain_accept_queue(std::mem::replace(&mut listener, primary));
+                        log!(Info, "listen_primary", { "listen_addr" => args.listen_addr }, "Listening on primary address {} again; the backup address is closed", args.listen_addr);
+                        primary_retry = None;
+                    }
+                    continue;
+                }
+            },
+        };
+        let (client, client_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) if is_transient_accept_error(&e) => {
+                let count: u64 = stats.accept_errors.fetch_add(1, Ordering::Relaxed) + 1;
+                log!(Error, "accept_failed", { "count" => count, "error" => e }, "Failed to accept connection ({} so far): {}", count, e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Addresses that haven't knocked are closed before anything is sent, so scanners learn nothing about the tunnel.
+        if knocks.as_ref().is_some_and(|knocks| !knocks.is_open(client_addr.ip())) {
+            log!(Info, "knock_missing", { "client" => client_addr }, "Refusing {}:{}: the address has not completed the knock sequence", client_addr.ip(), client_addr.port());
+            log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::PolicyDenied }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::PolicyDenied);
+            continue;
+        }
+
+        if !within_limits(&args, &stats, client_addr) {
+            log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::PolicyDenied }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::PolicyDenied);
+            send_busy_response(client, &args.busy_response_bytes, &lingering);
+            continue;
+        }
+        let (guard, stop) = ConnectionGuard::new(Arc::clone(&stats), client_addr);
+
+        // Warn once when the estimated descriptor usage crosses 90% of the limit, and re-arm below 80%.
+        if let Some(limit) = fd_limit {
+            let in_use: u64 = stats.active_connections.load(Ordering::Relaxed) * 2;
+            if !fd_warned && in_use * 10 >= limit * 9 {
+                log!(Warn, "nofile_limit_near", { "in_use" => in_use, "limit" => limit }, "Approaching open file limit: about {} of {} descriptors in use", in_use, limit);
+                fd_warned = true;
+            } else if fd_warned && in_use * 10 < limit * 8 {
+                fd_warned = false;
+            }
+        }
+
+        let args: Arc<Args> = Arc::clone(&args);
+        let dials: Arc<DialLimiter> = Arc::clone(&dials);
+        let stats: Arc<Stats> = Arc::clone(&stats);
+        let flows: Arc<FlowExporter> = Arc::clone(&flows);
+
+        // Spawn a new task to handle the client connection.
+        tokio::spawn(async move {
+            // If handling the client fails, print an error message.
+            // A stop request from the control socket drops the connection wherever it is.
+            tokio::select! {
+                result = handle_client(client, args, dials, stats, flows) => {
+                    if let Err(e) = result {
+                        log!(Error, "client_failed", { "error" => e }, "Failed to handle client: {}", e);
+                    }
+                }
+                _ = stop.notified() => {
+                    log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::Killed }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::Killed);
+                }
+            }
+            drop(guard);
+        });
+    }
+}
@@ -0,0 +1,243 @@
+//! Plain-text and JSON log lines, and the sinks they are written to.
+
+use crate::config::{Args, LogTarget};
+use crate::session::CloseReason;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// An opened log destination; stdout is used while none is set.
+pub enum LogSink {
+    File(Mutex<std::fs::File>),
+    #[cfg(unix)]
+    Syslog,
+    #[cfg(unix)]
+    Journald { socket: std::os::unix::net::UnixDatagram, tag: String },
+}
+
+/// The log destination, set once at startup from `--log-target`.
+pub static LOG_SINK: OnceLock<LogSink> = OnceLock::new();
+
+/// The path of the systemd journal's native protocol socket.
+#[cfg(unix)]
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Opens the log destination selected by `--log-target`.
+pub fn init_log_sink(args: &Args) -> io::Result<()> {
+    let sink: LogSink = match args.log_target {
+        LogTarget::Stdout => return Ok(()),
+        LogTarget::File => {
+            let path: &PathBuf = args.log_file.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "--log-file is required"))?;
+            LogSink::File(Mutex::new(std::fs::OpenOptions::new().create(true).append(true).open(path)?))
+        }
+        #[cfg(unix)]
+        LogTarget::Syslog => {
+            // `openlog` keeps the identifier pointer, so it has to live for the rest of the process.
+            let tag: &'static std::ffi::CStr = Box::leak(std::ffi::CString::new(args.log_tag.replace('\0', ""))?.into_boxed_c_str());
+            // SAFETY: `tag` is a valid NUL-terminated string that is never freed.
+            unsafe { libc::openlog(tag.as_ptr(), libc::LOG_PID | libc::LOG_NDELAY, args.syslog_facility.code()) };
+            LogSink::Syslog
+        }
+        #[cfg(unix)]
+        LogTarget::Journald => {
+            let socket = std::os::unix::net::UnixDatagram::unbound()?;
+            socket
+                .connect(JOURNALD_SOCKET)
+                .map_err(|e| io::Error::new(e.kind(), format!("failed to connect to {}: {}", JOURNALD_SOCKET, e)))?;
+            LogSink::Journald { socket, tag: args.log_tag.clone() }
+        }
+        #[cfg(not(unix))]
+        LogTarget::Syslog | LogTarget::Journald => {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "syslog and journald are not available on this platform"));
+        }
+    };
+
+    // Only called once from `main`, so the sink cannot already be set.
+    let _ = LOG_SINK.set(sink);
+    Ok(())
+}
+
+/// Sends one entry to the journal using its native protocol.
+///
+/// `MESSAGE` always uses the length-prefixed form, so messages may contain newlines.
+#[cfg(unix)]
+pub fn send_journald(socket: &std::os::unix::net::UnixDatagram, tag: &str, level: Level, message: &str) -> io::Result<()> {
+    let priority: u8 = match level {
+        Level::Info => 6,
+        Level::Warn => 4,
+        Level::Error => 3,
+    };
+
+    let mut entry: Vec<u8> = format!("PRIORITY={}\nSYSLOG_IDENTIFIER={}\nMESSAGE\n", priority, tag.replace('\n', " ")).into_bytes();
+    entry.extend_from_slice(&(message.len() as u64).to_le_bytes());
+    entry.extend_from_slice(message.as_bytes());
+    entry.push(b'\n');
+    socket.send(&entry)?;
+    Ok(())
+}
+
+/// Whether log lines are written as JSON objects, set once at startup from `--log-json`.
+pub static LOG_JSON: AtomicBool = AtomicBool::new(false);
+
+/// The severity of a log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+/// A value that can be attached to a log line as a JSON field.
+pub trait LogValue {
+    /// Appends the value to `out` as a JSON value.
+    fn write_json(&self, out: &mut String);
+}
+
+macro_rules! impl_log_number {
+    ($($ty:ty),*) => {
+        $(impl LogValue for $ty {
+            fn write_json(&self, out: &mut String) {
+                out.push_str(&self.to_string());
+            }
+        })*
+    };
+}
+
+macro_rules! impl_log_string {
+    ($($ty:ty),*) => {
+        $(impl LogValue for $ty {
+            fn write_json(&self, out: &mut String) {
+                write_json_string(out, &self.to_string());
+            }
+        })*
+    };
+}
+
+impl_log_number!(u8, u16, u64, usize);
+
+impl_log_string!(&str, String, IpAddr, SocketAddr, CloseReason, io::Error, Box<dyn std::error::Error>);
+
+impl LogValue for bool {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(if *self { "true" } else { "false" });
+    }
+}
+
+impl<T: LogValue> LogValue for Option<T> {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Some(value) => value.write_json(out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl LogValue for f64 {
+    fn write_json(&self, out: &mut String) {
+        if self.is_finite() {
+            out.push_str(&format!("{:.1}", self));
+        } else {
+            out.push_str("null");
+        }
+    }
+}
+
+/// Appends `value` to `out` as a quoted, escaped JSON string.
+pub fn write_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes one log line to the configured sink.
+///
+/// In text mode this is `[LEVEL] - message`; syslog and journald get the message alone,
+/// since they record the level themselves. With `--log-json` it is an object holding
+/// `level`, `event`, `msg` and the given fields, so scrapers can key on `event` and the
+/// field names rather than on the wording of the message.
+pub fn emit_log(level: Level, event: &str, fields: &[(&str, &dyn LogValue)], message: fmt::Arguments<'_>) {
+    let (tag, name) = match level {
+        Level::Info => ("[INFO]", "info"),
+        Level::Warn => ("[WARN]", "warn"),
+        Level::Error => ("[ERROR]", "error"),
+    };
+
+    let json: bool = LOG_JSON.load(Ordering::Relaxed);
+    let body: String = if json {
+        let mut line = String::from("{\"level\":");
+        write_json_string(&mut line, name);
+        line.push_str(",\"event\":");
+        write_json_string(&mut line, event);
+        line.push_str(",\"msg\":");
+        write_json_string(&mut line, &message.to_string());
+        for (key, value) in fields {
+            line.push(',');
+            write_json_string(&mut line, key);
+            line.push(':');
+            value.write_json(&mut line);
+        }
+        line.push('}');
+        line
+    } else {
+        message.to_string()
+    };
+    let line: String = if json { body.clone() } else { format!("{} - {}", tag, body) };
+
+    // A sink that fails falls back to stderr, so the line is not lost.
+    let result: io::Result<()> = match LOG_SINK.get() {
+        None => {
+            if level == Level::Info {
+                println!("{}", line);
+            } else {
+                eprintln!("{}", line);
+            }
+            Ok(())
+        }
+        Some(LogSink::File(file)) => {
+            use std::io::Write;
+            writeln!(file.lock().unwrap(), "{}", line)
+        }
+        #[cfg(unix)]
+        Some(LogSink::Syslog) => {
+            let priority: libc::c_int = match level {
+                Level::Info => libc::LOG_INFO,
+                Level::Warn => libc::LOG_WARNING,
+                Level::Error => libc::LOG_ERR,
+            };
+            let message = std::ffi::CString::new(body.replace('\0', "")).unwrap_or_default();
+            // SAFETY: the format string takes exactly one string argument, and `message` is NUL-terminated.
+            unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+            Ok(())
+        }
+        #[cfg(unix)]
+        Some(LogSink::Journald { socket, tag }) => send_journald(socket, tag, level, &body),
+    };
+    if let Err(e) = result {
+        eprintln!("{} (log sink failed: {})", line, e);
+    }
+}
+
+/// Logs a line through `emit_log`: `log!(Level, "event", { "field" => value, ... }, "format", args...)`.
+macro_rules! log {
+    ($level:ident, $event:literal, { $($key:literal => $value:expr),* $(,)? }, $($arg:tt)+) => {
+        $crate::logging::emit_log(
+            $crate::logging::Level::$level,
+            $event,
+            &[$(($key, &$value as &dyn $crate::logging::LogValue)),*],
+            format_args!($($arg)+),
+        )
+    };
+}
@@ -0,0 +1,83 @@
+//! Heap usage accounting through a counting global allocator, and of the connection buffers within it.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of bytes currently allocated on the heap.
+pub static HEAP_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of bytes currently held in connection buffers: read buffers, coalescer queues
+/// and the buffers of payload stages, banner checks and echo sessions.
+pub static BUFFER_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting the bytes it hands out in `HEAP_BYTES`.
+pub struct CountingAllocator;
+
+// SAFETY: every call is forwarded unchanged to `System`; only the counter is added.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr: *mut u8 = System.alloc(layout);
+        if !ptr.is_null() {
+            HEAP_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr: *mut u8 = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            HEAP_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        HEAP_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr: *mut u8 = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            HEAP_BYTES.fetch_add(new_size, Ordering::Relaxed);
+            HEAP_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+/// Returns the number of bytes currently allocated on the heap.
+pub fn heap_bytes() -> usize {
+    HEAP_BYTES.load(Ordering::Relaxed)
+}
+
+/// Returns the number of bytes currently held in connection buffers.
+pub fn buffer_bytes() -> usize {
+    BUFFER_BYTES.load(Ordering::Relaxed)
+}
+
+/// Counts a connection buffer's size in `BUFFER_BYTES` for as long as the account is alive.
+pub struct BufferAccount {
+    bytes: usize,
+}
+
+impl BufferAccount {
+    /// Starts counting a buffer of `bytes` bytes.
+    pub fn new(bytes: usize) -> Self {
+        BUFFER_BYTES.fetch_add(bytes, Ordering::Relaxed);
+        BufferAccount { bytes }
+    }
+
+    /// Updates the counted size after the buffer grew or shrank.
+    pub fn set(&mut self, bytes: usize) {
+        BUFFER_BYTES.fetch_add(bytes, Ordering::Relaxed);
+        BUFFER_BYTES.fetch_sub(self.bytes, Ordering::Relaxed);
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for BufferAccount {
+    fn drop(&mut self) {
+        BUFFER_BYTES.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,347 @@
+//! A small regular expression matcher over raw bytes, for checking the first data of a connection.
+//!
+//! It supports literals, `.`, bracket classes (`[a-z]`, `[^\r\n]`), the `\d`, `\w` and `\s` classes,
+//! the `*`, `+` and `?` repetitions and the `^` and `$` anchors. Escapes follow `--payload`
+//! (`\r`, `\n`, `\t`, `\xNN`), and a backslash before any other character matches it literally.
+//! There is no alternation or grouping.
+
+use std::fmt;
+
+/// What a single position in the pattern matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Atom {
+    /// Exactly this byte.
+    Byte(u8),
+    /// Any byte.
+    Any,
+    /// Any byte inside (or, when negated, outside) these inclusive ranges.
+    Class { ranges: Vec<(u8, u8)>, negated: bool },
+}
+
+impl Atom {
+    /// Returns `true` if the atom matches `b`.
+    fn matches(&self, b: u8) -> bool {
+        match self {
+            Atom::Byte(expected) => *expected == b,
+            Atom::Any => true,
+            Atom::Class { ranges, negated } => ranges.iter().any(|(low, high)| (*low..=*high).contains(&b)) != *negated,
+        }
+    }
+}
+
+/// How many times an atom may repeat; `+` is parsed as the atom followed by its `*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repeat {
+    One,
+    ZeroOrOne,
+    ZeroOrMore,
+}
+
+/// An atom together with its repetition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Node {
+    atom: Atom,
+    repeat: Repeat,
+}
+
+/// A compiled pattern, parsed from the command line with `parse_pattern`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    source: String,
+    nodes: Vec<Node>,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Pattern {
+    /// Returns `true` if the pattern matches anywhere in `data` (or at its start, with `^`).
+    pub fn is_match(&self, data: &[u8]) -> bool {
+        self.run(data).is_none()
+    }
+
+    /// Returns `false` once no data starting with `data` can match, so waiting for more is pointless.
+    ///
+    /// Only a pattern anchored with `^` can rule a prefix out; any other may still match later data.
+    pub fn could_match(&self, data: &[u8]) -> bool {
+        self.run(data).is_none_or(|states| states.contains(&true))
+    }
+
+    /// Runs the pattern over `data`, tracking every node it could be at in parallel.
+    ///
+    /// State `i` means the nodes before `i` have matched, and state `nodes.len()` means the
+    /// whole pattern has. Returns `None` on a match, or the states left after `data` otherwise,
+    /// so matching takes time linear in the length of `data` whatever the pattern.
+    fn run(&self, data: &[u8]) -> Option<Vec<bool>> {
+        let accept: usize = self.nodes.len();
+        let mut states: Vec<bool> = vec![false; accept + 1];
+        self.add_state(&mut states, 0);
+        for b in data {
+            if states[accept] && !self.anchored_end {
+                return None;
+            }
+            let mut next: Vec<bool> = vec![false; accept + 1];
+            for (i, node) in self.nodes.iter().enumerate() {
+                if states[i] && node.atom.matches(*b) {
+                    self.add_state(&mut next, if node.repeat == Repeat::ZeroOrMore { i } else { i + 1 });
+                }
+            }
+            // Without `^`, a match may also start at the next byte.
+            if !self.anchored_start {
+                self.add_state(&mut next, 0);
+            }
+            states = next;
+        }
+        if states[accept] {
+            None
+        } else {
+            Some(states)
+        }
+    }
+
+    /// Adds `state` to `states`, along with the states after it that optional nodes let it skip to.
+    fn add_state(&self, states: &mut [bool], mut state: usize) {
+        while !states[state] {
+            states[state] = true;
+            match self.nodes.get(state) {
+                Some(node) if node.repeat != Repeat::One => state += 1,
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Parses the escape after a backslash, returning the atom and the number of characters used.
+fn parse_escape(chars: &[char]) -> Result<(Atom, usize), String> {
+    let digits: Vec<(u8, u8)> = vec![(b'0', b'9')];
+    let word: Vec<(u8, u8)> = vec![(b'0', b'9'), (b'a', b'z'), (b'A', b'Z'), (b'_', b'_')];
+    let space: Vec<(u8, u8)> = vec![(b' ', b' '), (b'\t', b'\r')];
+    match chars.first() {
+        None => Err("pattern ends with a backslash".to_string()),
+        Some('r') => Ok((Atom::Byte(b'\r'), 1)),
+        Some('n') => Ok((Atom::Byte(b'\n'), 1)),
+        Some('t') => Ok((Atom::Byte(b'\t'), 1)),
+        Some('d') => Ok((Atom::Class { ranges: digits, negated: false }, 1)),
+        Some('D') => Ok((Atom::Class { ranges: digits, negated: true }, 1)),
+        Some('w') => Ok((Atom::Class { ranges: word, negated: false }, 1)),
+        Some('W') => Ok((Atom::Class { ranges: word, negated: true }, 1)),
+        Some('s') => Ok((Atom::Class { ranges: space, negated: false }, 1)),
+        Some('S') => Ok((Atom::Class { ranges: space, negated: true }, 1)),
+        Some('x') => {
+            let hex: String = chars[1..].iter().take(2).collect();
+            let value: u8 = Some(&hex)
+                .filter(|h| h.len() == 2)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+                .ok_or_else(|| "invalid \\x escape in pattern".to_string())?;
+            Ok((Atom::Byte(value), 3))
+        }
+        Some(c) if c.is_ascii() => Ok((Atom::Byte(*c as u8), 1)),
+        Some(c) => Err(format!("non-ASCII character {:?} in pattern; use \\xNN", c)),
+    }
+}
+
+/// Parses a bracket class starting after its `[`, returning the atom and the number of characters used.
+fn parse_class(chars: &[char]) -> Result<(Atom, usize), String> {
+    let negated: bool = chars.first() == Some(&'^');
+    let mut i: usize = usize::from(negated);
+    let mut ranges: Vec<(u8, u8)> = Vec::new();
+    loop {
+        let low: u8 = match chars.get(i) {
+            None => return Err("unterminated [ in pattern".to_string()),
+            Some(']') if i > usize::from(negated) => return Ok((Atom::Class { ranges, negated }, i + 1)),
+            Some('\\') => match parse_escape(&chars[i + 1..])? {
+                (Atom::Byte(b), used) => {
+                    i += used + 1;
+                    b
+                }
+                (Atom::Class { ranges: class, negated: false }, used) => {
+                    ranges.extend(class);
+                    i += used + 1;
+                    continue;
+                }
+                _ => return Err("negated classes cannot appear inside [ ]".to_string()),
+            },
+            Some(c) if c.is_ascii() => {
+                i += 1;
+                *c as u8
+            }
+            Some(c) => return Err(format!("non-ASCII character {:?} in pattern; use \\xNN", c)),
+        };
+        // A `-` between two bytes makes a range; anywhere else it is literal.
+        if chars.get(i) == Some(&'-') && chars.get(i + 1).is_some_and(|c| *c != ']') {
+            let high: u8 = match chars[i + 1] {
+                '\\' => match parse_escape(&chars[i + 2..])? {
+                    (Atom::Byte(b), used) => {
+                        i += used + 2;
+                        b
+                    }
+                    _ => return Err("a class cannot end a range".to_string()),
+                },
+                c if c.is_ascii() => {
+                    i += 2;
+                    c as u8
+                }
+                c => return Err(format!("non-ASCII character {:?} in pattern; use \\xNN", c)),
+            };
+            if high < low {
+                return Err(format!("range {:?}-{:?} is out of order", low as char, high as char));
+            }
+            ranges.push((low, high));
+        } else {
+            ranges.push((low, low));
+        }
+    }
+}
+
+/// Parses a pattern given on the command line.
+pub fn parse_pattern(input: &str) -> Result<Pattern, String> {
+    let mut chars: Vec<char> = input.chars().collect();
+    let anchored_start: bool = chars.first() == Some(&'^');
+    if anchored_start {
+        chars.remove(0);
+    }
+    // A trailing `$` anchors the end, unless it is escaped.
+    let trailing_backslashes: usize = chars.iter().rev().skip(1).take_while(|c| **c == '\\').count();
+    let anchored_end: bool = chars.last() == Some(&'$') && trailing_backslashes.is_multiple_of(2);
+    if anchored_end {
+        chars.pop();
+    }
+
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut i: usize = 0;
+    while i < chars.len() {
+        let (atom, used): (Atom, usize) = match chars[i] {
+            '.' => (Atom::Any, 1),
+            '[' => {
+                let (atom, used) = parse_class(&chars[i + 1..])?;
+                (atom, used + 1)
+            }
+            '\\' => {
+                let (atom, used) = parse_escape(&chars[i + 1..])?;
+                (atom, used + 1)
+            }
+            '*' | '+' | '?' => return Err(format!("{:?} has nothing to repeat", chars[i])),
+            '(' | ')' | '|' | '{' | '}' | '^' | '$' => return Err(format!("{:?} is not supported in patterns; escape it to match it literally", chars[i])),
+            c if c.is_ascii() => (Atom::Byte(c as u8), 1),
+            c => return Err(format!("non-ASCII character {:?} in pattern; use \\xNN", c)),
+        };
+        i += used;
+        let repeat: Repeat = match chars.get(i) {
+            Some('*') => Repeat::ZeroOrMore,
+            Some('+') => {
+                nodes.push(Node { atom: atom.clone(), repeat: Repeat::One });
+                Repeat::ZeroOrMore
+            }
+            Some('?') => Repeat::ZeroOrOne,
+            _ => Repeat::One,
+        };
+        if chars.get(i).is_some_and(|c| matches!(c, '*' | '+' | '?')) {
+            i += 1;
+        }
+        nodes.push(Node { atom, repeat });
+    }
+    Ok(Pattern { source: input.to_string(), nodes, anchored_start, anchored_end })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `pattern`, which the tests expect to be valid.
+    fn pattern(source: &str) -> Pattern {
+        parse_pattern(source).unwrap()
+    }
+
+    #[test]
+    fn literals_match_anywhere() {
+        assert!(pattern("GET").is_match(b"xGETy"));
+        assert!(!pattern("GET").is_match(b"GE T"));
+        assert!(pattern("").is_match(b""));
+    }
+
+    #[test]
+    fn dot_matches_any_byte() {
+        assert!(pattern("a.c").is_match(b"a\x00c"));
+        assert!(!pattern("a.c").is_match(b"ac"));
+    }
+
+    #[test]
+    fn classes_match_their_ranges() {
+        assert!(pattern("[a-c]x").is_match(b"bx"));
+        assert!(!pattern("[a-c]x").is_match(b"dx"));
+        assert!(pattern("[^\\r\\n]").is_match(b"a"));
+        assert!(!pattern("^[^\\r\\n]").is_match(b"\r"));
+        assert!(pattern("[-a]").is_match(b"-"));
+        assert!(pattern("^\\d\\w\\s$").is_match(b"7_ "));
+        assert!(!pattern("\\D").is_match(b"123"));
+    }
+
+    #[test]
+    fn escapes_match_literally() {
+        assert!(pattern("\\x16\\x03").is_match(&[0x16, 0x03, 0x01]));
+        assert!(pattern("a\\.b").is_match(b"a.b"));
+        assert!(!pattern("a\\.b").is_match(b"axb"));
+        assert!(pattern("cost\\$").is_match(b"cost$5"));
+    }
+
+    #[test]
+    fn question_mark_makes_an_atom_optional() {
+        assert!(pattern("^ab?c$").is_match(b"ac"));
+        assert!(pattern("^ab?c$").is_match(b"abc"));
+        assert!(!pattern("^ab?c$").is_match(b"abbc"));
+    }
+
+    #[test]
+    fn star_repeats_zero_or_more_times() {
+        assert!(pattern("^ab*c$").is_match(b"ac"));
+        assert!(pattern("^ab*c$").is_match(b"abbbc"));
+        assert!(!pattern("^ab*c$").is_match(b"abxc"));
+    }
+
+    #[test]
+    fn plus_repeats_one_or_more_times() {
+        assert!(!pattern("^ab+c$").is_match(b"ac"));
+        assert!(pattern("^ab+c$").is_match(b"abc"));
+        assert!(pattern("^ab+c$").is_match(b"abbbc"));
+    }
+
+    #[test]
+    fn anchors_pin_the_ends() {
+        assert!(pattern("^GET ").is_match(b"GET /"));
+        assert!(!pattern("^GET ").is_match(b" GET /"));
+        assert!(pattern("HTTP/1\\.1\\r\\n$").is_match(b"GET / HTTP/1.1\r\n"));
+        assert!(!pattern("HTTP/1\\.1$").is_match(b"HTTP/1.1\r\n"));
+        assert!(pattern("^$").is_match(b""));
+        assert!(!pattern("^$").is_match(b"x"));
+    }
+
+    #[test]
+    fn repetitions_that_overlap_run_in_linear_time() {
+        let data: Vec<u8> = vec![b'a'; 10_000];
+        assert!(!pattern("a*a*a*a*a*a*a*a*a*a*b").is_match(&data));
+    }
+
+    #[test]
+    fn anchored_prefixes_are_ruled_out_early() {
+        assert!(pattern("^SSH-2\\.0-").could_match(b"SSH"));
+        assert!(pattern("^SSH-2\\.0-").could_match(b"SSH-2.0-OpenSSH"));
+        assert!(!pattern("^SSH-2\\.0-").could_match(b"HTTP"));
+        assert!(!pattern("^OK$").could_match(b"OK\r\n"));
+        assert!(pattern("OK$").could_match(b"NO"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        assert!(parse_pattern("*a").is_err());
+        assert!(parse_pattern("a|b").is_err());
+        assert!(parse_pattern("[abc").is_err());
+        assert!(parse_pattern("[z-a]").is_err());
+        assert!(parse_pattern("\\x4").is_err());
+        assert!(parse_pattern("a\\").is_err());
+    }
+}
@@ -0,0 +1,779 @@
+//! The building blocks of the forwarding loops between client and server.
+
+use crate::config::{Args, Mode};
+use crate::flow::FlowCounters;
+use crate::ftp::FtpRewriter;
+use crate::listener::Stats;
+use crate::memory::BufferAccount;
+use crate::session::CloseReason;
+use std::io;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time::Instant;
+
+/// State shared by both forwarding directions of one connection.
+pub struct PipeState {
+    pub client_addr: SocketAddr,
+    /// The proxy's address as seen by the client.
+    pub local_addr: SocketAddr,
+    pub target_addr: SocketAddr,
+    pub budget: ByteBudget,
+    pub flow: FlowCounters,
+    /// Set until the first client data has been forwarded, if that data should be fragmented.
+    hello_pending: AtomicBool,
+    /// When the target's first data was forwarded to the client.
+    pub first_response: OnceLock<Instant>,
+}
+
+impl PipeState {
+    /// Creates the state for a connection from `client_addr`, accepted on `local_addr` and
+    /// forwarded to `target_addr`, limited to `max_bytes` (zero for unlimited).
+    /// With `fragment_hello`, the first client data is checked for a ClientHello to fragment.
+    pub fn new(client_addr: SocketAddr, local_addr: SocketAddr, target_addr: SocketAddr, max_bytes: u64, fragment_hello: bool) -> Self {
+        PipeState {
+            client_addr,
+            local_addr,
+            target_addr,
+            budget: ByteBudget::new(max_bytes),
+            flow: FlowCounters::default(),
+            hello_pending: AtomicBool::new(fragment_hello),
+            first_response: OnceLock::new(),
+        }
+    }
+
+    /// Returns `true` the first time it is called if the first client data should be fragmented.
+    pub fn take_hello(&self) -> bool {
+        self.hello_pending.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// The TLS record type of handshake messages, which carries the ClientHello.
+pub const TLS_HANDSHAKE: u8 = 0x16;
+
+/// Splits `data` into pieces of `size` bytes if it starts with a TLS handshake record.
+///
+/// With `records`, the first record's body is cut up instead and each piece gets a record
+/// header of its own, so the ClientHello spans several records as well as several segments.
+/// Anything after that record follows as a last piece. Returns `None` if `data` is not a
+/// TLS handshake, or if `records` is set and the first record did not arrive whole.
+pub fn fragment_client_hello(data: &[u8], size: usize, records: bool) -> Option<Vec<Vec<u8>>> {
+    if size == 0 || data.len() < 5 || data[0] != TLS_HANDSHAKE || data[1] != 0x03 {
+        return None;
+    }
+    if !records {
+        return Some(data.chunks(size).map(<[u8]>::to_vec).collect());
+    }
+
+    let length: usize = u16::from_be_bytes([data[3], data[4]]) as usize;
+    let body: &[u8] = data.get(5..5 + length)?;
+    let mut pieces: Vec<Vec<u8>> = body
+        .chunks(size)
+        .map(|chunk| {
+            let mut record: Vec<u8> = data[..3].to_vec();
+            record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            record.extend_from_slice(chunk);
+            record
+        })
+        .collect();
+    if data.len() > 5 + length {
+        pieces.push(data[5 + length..].to_vec());
+    }
+    Some(pieces)
+}
+
+/// Writes `pieces` one at a time, pausing `delay` between them.
+///
+/// The socket must have `TCP_NODELAY` set for the pieces to leave as separate segments.
+pub async fn write_fragments<W: AsyncWrite + Unpin>(writer: &mut W, pieces: &[Vec<u8>], delay: Duration) -> io::Result<()> {
+    for (i, piece) in pieces.iter().enumerate() {
+        if i > 0 && !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        writer.write_all(piece).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Appends the bytes forwarded in one direction to a file, with `--record-dir`.
+///
+/// Recording is best effort: if the file cannot be written, a warning is logged and the
+/// session carries on unrecorded.
+pub struct Recorder {
+    file: Option<tokio::fs::File>,
+    path: PathBuf,
+}
+
+impl Recorder {
+    /// Creates `name` in `dir`, or a recorder that records nothing if `dir` is `None`.
+    pub async fn create(dir: Option<&Path>, name: String) -> Self {
+        let Some(dir) = dir else {
+            return Recorder { file: None, path: PathBuf::new() };
+        };
+        let path: PathBuf = dir.join(name);
+        let file: Option<tokio::fs::File> = match tokio::fs::File::create(&path).await {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log!(Warn, "record_failed", { "path" => path.display().to_string(), "error" => e }, "Failed to record to {}: {}", path.display(), e);
+                None
+            }
+        };
+        Recorder { file, path }
+    }
+
+    /// Appends `data` to the recording.
+    pub async fn record(&mut self, data: &[u8]) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        if let Err(e) = file.write_all(data).await {
+            log!(Warn, "record_failed", { "path" => self.path.display().to_string(), "error" => e }, "Failed to record to {}: {}", self.path.display(), e);
+            self.file = None;
+        }
+    }
+}
+
+/// What `PacketSkipper::filter` decided for a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Skip<'a> {
+    /// The packet is past the skip and should be forwarded.
+    Forward(&'a [u8]),
+    /// The packet was one of the skipped ones.
+    Dropped,
+    /// The skipped packets now add up to more than the handshake limit.
+    LimitExceeded,
+}
+
+/// Drops the first packets a client sends, as configured by `--skip`, and then the first
+/// bytes, as configured by `--skip-bytes`.
+///
+/// A packet is whatever a single read returned, so what `--skip` drops depends on how the
+/// client's data happened to be split; `--skip-bytes` drops the same bytes however it arrives.
+/// The bytes of dropped packets are counted against `--max-handshake-bytes` so the skip
+/// phase cannot absorb unbounded data.
+pub struct PacketSkipper {
+    remaining: usize,
+    remaining_bytes: usize,
+    dropped: usize,
+    max_dropped: usize,
+}
+
+impl PacketSkipper {
+    /// Creates a skipper dropping `skip` packets of at most `max_dropped` bytes in total (zero for unlimited),
+    /// followed by `skip_bytes` bytes.
+    pub fn new(skip: usize, skip_bytes: usize, max_dropped: usize) -> Self {
+        PacketSkipper { remaining: skip, remaining_bytes: skip_bytes, dropped: 0, max_dropped }
+    }
+
+    /// Returns the handshake byte limit.
+    pub fn max_dropped(&self) -> usize {
+        self.max_dropped
+    }
+
+    /// Decides what happens to the next packet.
+    pub fn filter<'a>(&mut self, packet: &'a [u8]) -> Skip<'a> {
+        if self.remaining == 0 {
+            let skipped: usize = self.remaining_bytes.min(packet.len());
+            self.remaining_bytes -= skipped;
+            return if skipped == packet.len() && skipped > 0 { Skip::Dropped } else { Skip::Forward(&packet[skipped..]) };
+        }
+        self.remaining -= 1;
+        self.dropped += packet.len();
+        if self.max_dropped > 0 && self.dropped > self.max_dropped {
+            Skip::LimitExceeded
+        } else {
+            Skip::Dropped
+        }
+    }
+}
+
+/// A per-connection transfer allowance shared by both forwarding directions.
+pub struct ByteBudget {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl ByteBudget {
+    /// Creates a budget of `limit` bytes, or an unlimited one if `limit` is zero.
+    pub fn new(limit: u64) -> Self {
+        ByteBudget { limit, used: AtomicU64::new(0) }
+    }
+
+    /// Claims `n` bytes and returns how many of them may still be forwarded.
+    ///
+    /// A return value smaller than `n` means the budget is exhausted and the connection should close.
+    pub fn claim(&self, n: usize) -> usize {
+        if self.limit == 0 {
+            return n;
+        }
+        let used: u64 = self.used.fetch_add(n as u64, Ordering::Relaxed);
+        self.limit.saturating_sub(used).min(n as u64) as usize
+    }
+}
+
+/// Removes a configured prefix (a number of lines or bytes) from the start of the server's response.
+///
+/// The prefix may be split over any number of reads; once it has been consumed,
+/// all further data passes through untouched.
+pub struct ResponseStripper {
+    lines: usize,
+    bytes: usize,
+}
+
+impl ResponseStripper {
+    /// Creates a stripper that drops the first `lines` lines and then the first `bytes` bytes.
+    pub fn new(lines: usize, bytes: usize) -> Self {
+        ResponseStripper { lines, bytes }
+    }
+
+    /// Returns the part of `data` that should be forwarded to the client.
+    pub fn strip<'a>(&mut self, mut data: &'a [u8]) -> &'a [u8] {
+        // Drop whole lines, including their terminating newline.
+        while self.lines > 0 && !data.is_empty() {
+            match data.iter().position(|&b| b == b'\n') {
+                Some(i) => {
+                    data = &data[i + 1..];
+                    self.lines -= 1;
+                }
+                None => data = &[],
+            }
+        }
+
+        // Drop raw bytes.
+        let n: usize = self.bytes.min(data.len());
+        self.bytes -= n;
+        &data[n..]
+    }
+}
+
+/// The connection preface every HTTP/2 client sends before its first frame.
+pub const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The HTTP/2 `HEADERS` frame type.
+pub const H2_FRAME_HEADERS: u8 = 0x1;
+
+/// The HTTP/2 `RST_STREAM` frame type.
+pub const H2_FRAME_RST_STREAM: u8 = 0x3;
+
+/// Per-connection HTTP/2 counters, shared by the trackers on both directions.
+#[derive(Default)]
+pub struct H2Counters {
+    /// Set once the client has sent the HTTP/2 connection preface.
+    pub detected: AtomicBool,
+    pub streams: AtomicU64,
+    pub client_resets: AtomicU64,
+    pub server_resets: AtomicU64,
+}
+
+/// Passively follows the HTTP/2 frames in one direction of a connection.
+///
+/// Only the 9-byte frame headers are looked at; payloads are skipped over and the
+/// bytes themselves are forwarded unchanged. The client side must start with the
+/// connection preface, otherwise tracking stops for that connection.
+pub struct H2Tracker {
+    active: bool,
+    client: bool,
+    counters: Arc<H2Counters>,
+    preface: usize,
+    header: Vec<u8>,
+    remaining: usize,
+    last_stream: u32,
+}
+
+impl H2Tracker {
+    /// Creates a tracker for the client-to-server (`client == true`) or server-to-client direction.
+    pub fn new(active: bool, client: bool, counters: Arc<H2Counters>) -> Self {
+        let preface: usize = if client { 0 } else { H2_PREFACE.len() };
+        H2Tracker { active, client, counters, preface, header: Vec::with_capacity(9), remaining: 0, last_stream: 0 }
+    }
+
+    /// Feeds the next chunk of forwarded data through the frame parser.
+    pub fn observe(&mut self, mut data: &[u8]) {
+        if !self.active {
+            return;
+        }
+
+        // Match the connection preface, which may be split over several reads.
+        if self.preface < H2_PREFACE.len() {
+            let n: usize = (H2_PREFACE.len() - self.preface).min(data.len());
+            if data[..n] != H2_PREFACE[self.preface..self.preface + n] {
+                self.active = false;
+                return;
+            }
+            self.preface += n;
+            data = &data[n..];
+            if self.preface == H2_PREFACE.len() {
+                self.counters.detected.store(true, Ordering::Relaxed);
+            }
+        }
+
+        while !data.is_empty() {
+            // Skip the payload of the current frame.
+            if self.remaining > 0 {
+                let n: usize = self.remaining.min(data.len());
+                self.remaining -= n;
+                data = &data[n..];
+                continue;
+            }
+
+            // Collect the next frame header.
+            let n: usize = (9 - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.header.len() == 9 {
+                self.frame();
+            }
+        }
+    }
+
+    /// Accounts for the frame whose header has just been read.
+    pub fn frame(&mut self) {
+        let h: &[u8] = &self.header;
+        let length: usize = u32::from_be_bytes([0, h[0], h[1], h[2]]) as usize;
+        let stream: u32 = u32::from_be_bytes([h[5], h[6], h[7], h[8]]) & 0x7fff_ffff;
+
+        match h[3] {
+            // Stream IDs only ever increase, so a HEADERS frame on a higher ID opens a new stream;
+            // anything else is a trailer on an existing one.
+            H2_FRAME_HEADERS if self.client && stream > self.last_stream => {
+                self.last_stream = stream;
+                self.counters.streams.fetch_add(1, Ordering::Relaxed);
+            }
+            H2_FRAME_RST_STREAM if self.client => {
+                self.counters.client_resets.fetch_add(1, Ordering::Relaxed);
+            }
+            H2_FRAME_RST_STREAM => {
+                self.counters.server_resets.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+
+        self.header.clear();
+        self.remaining = length;
+    }
+}
+
+/// Buffers small chunks and writes them out together with vectored writes.
+///
+/// Data is flushed once `threshold` bytes are pending or `interval` has passed since the
+/// oldest pending chunk was buffered, whichever comes first. A disabled coalescer writes
+/// every chunk straight through.
+pub struct Coalescer {
+    enabled: bool,
+    threshold: usize,
+    interval: Duration,
+    chunks: Vec<Vec<u8>>,
+    pending: usize,
+    deadline: Option<Instant>,
+    account: BufferAccount,
+}
+
+impl Coalescer {
+    /// Creates a coalescer with the given flush threshold and interval.
+    pub fn new(enabled: bool, threshold: usize, interval: Duration) -> Self {
+        Coalescer { enabled, threshold, interval, chunks: Vec::new(), pending: 0, deadline: None, account: BufferAccount::new(0) }
+    }
+
+    /// Returns the instant by which buffered data must be flushed, if any is buffered.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Writes `data` to `writer`, or buffers it until the next flush when coalescing is enabled.
+    pub async fn write<W: AsyncWrite + Unpin>(&mut self, writer: &mut W, data: &[u8]) -> io::Result<()> {
+        if !self.enabled {
+            return writer.write_all(data).await;
+        }
+
+        self.chunks.push(data.to_vec());
+        self.pending += data.len();
+        self.account.set(self.pending);
+        self.deadline.get_or_insert_with(|| Instant::now() + self.interval);
+        if self.pending >= self.threshold {
+            self.flush(writer).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes out all buffered chunks, retrying until every byte has been written.
+    pub async fn flush<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> io::Result<()> {
+        let mut slices: Vec<IoSlice<'_>> = self.chunks.iter().map(|chunk| IoSlice::new(chunk)).collect();
+        let mut remaining: &mut [IoSlice<'_>] = &mut slices;
+
+        while !remaining.is_empty() {
+            let n: usize = writer.write_vectored(remaining).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            IoSlice::advance_slices(&mut remaining, n);
+        }
+
+        self.chunks.clear();
+        self.pending = 0;
+        self.account.set(0);
+        self.deadline = None;
+        Ok(())
+    }
+}
+
+/// Forwards data from the client to the server until either side closes or a limit is hit.
+pub async fn client_to_server(
+    mut client_read: OwnedReadHalf,
+    mut server_write: OwnedWriteHalf,
+    state: Arc<PipeState>,
+    args: Arc<Args>,
+    mut skipper: PacketSkipper,
+    mut h2: H2Tracker,
+    mut recorder: Recorder,
+) -> CloseReason {
+    let client_addr: SocketAddr = state.client_addr;
+    let mut coalescer: Coalescer = Coalescer::new(args.coalesce, args.coalesce_bytes, Duration::from_millis(args.coalesce_interval_ms));
+    let mut buffer: Vec<u8> = vec![0; args.client_buffer_size as usize]; // Buffer for reading data.
+    let _account: BufferAccount = BufferAccount::new(buffer.capacity());
+
+    loop {
+        // While coalesced data is pending, also wake up when it is due to be flushed.
+        let result: io::Result<usize> = match coalescer.deadline() {
+            Some(deadline) => tokio::select! {
+                result = client_read.read(&mut buffer) => result,
+                _ = tokio::time::sleep_until(deadline) => {
+                    if let Err(e) = coalescer.flush(&mut server_write).await {
+                        log!(Error, "write_failed", { "client" => client_addr, "peer" => "server", "error" => e }, "Failed to write to server: {}", e);
+                        break CloseReason::ServerError;
+                    }
+                    continue;
+                }
+            },
+            None => client_read.read(&mut buffer).await,
+        };
+
+        match result {
+            // End of stream: flush what is left and break the loop.
+            Ok(0) => {
+                if let Err(e) = coalescer.flush(&mut server_write).await {
+                    log!(Error, "write_failed", { "client" => client_addr, "peer" => "server", "error" => e }, "Failed to write to server: {}", e);
+                    break CloseReason::ServerError;
+                }
+                break CloseReason::ClientEof;
+            }
+            // Read data from the client and skip packets based on the `skip` argument.
+            Ok(n) => match skipper.filter(&buffer[..n]) {
+                Skip::Forward(data) => {
+                    // Forward the packet to the server, cut short if it runs over the byte limit.
+                    let allowed: usize = state.budget.claim(data.len());
+                    h2.observe(&data[..allowed]);
+                    let fragments: Option<Vec<Vec<u8>>> = state
+                        .take_hello()
+                        .then(|| fragment_client_hello(&data[..allowed], args.hello_fragment_size, args.hello_fragment_records))
+                        .flatten();
+                    let result: io::Result<()> = match fragments {
+                        Some(pieces) => write_fragments(&mut server_write, &pieces, Duration::from_millis(args.hello_fragment_delay_ms)).await,
+                        None => coalescer.write(&mut server_write, &data[..allowed]).await,
+                    };
+                    if let Err(e) = result {
+                        log!(Error, "write_failed", { "client" => client_addr, "peer" => "server", "error" => e }, "Failed to write to server: {}", e);
+                        break CloseReason::ServerError;
+                    }
+                    state.flow.up(allowed);
+                    recorder.record(&data[..allowed]).await;
+                    if allowed < data.len() {
+                        if let Err(e) = coalescer.flush(&mut server_write).await {
+                            log!(Error, "write_failed", { "client" => client_addr, "peer" => "server", "error" => e }, "Failed to write to server: {}", e);
+                            break CloseReason::ServerError;
+                        }
+                        break CloseReason::ByteLimit;
+                    }
+                }
+                Skip::Dropped => {}
+                // Skipped packets are never forwarded, so bound how much the client may send this way.
+                Skip::LimitExceeded => {
+                    log!(Warn, "handshake_limit", { "client" => client_addr, "limit" => skipper.max_dropped() }, "Client {}:{} sent more than {} handshake bytes", client_addr.ip(), client_addr.port(), skipper.max_dropped());
+                    break CloseReason::PolicyDenied;
+                }
+            },
+            // If reading from the client fails, log the error and break the loop.
+            Err(e) => {
+                log!(Error, "read_failed", { "client" => client_addr, "peer" => "client", "error" => e }, "Failed to read from client: {}", e);
+                break CloseReason::ClientError;
+            }
+        }
+    }
+}
+
+/// Forwards data from the server to the client until either side closes or a limit is hit.
+pub async fn server_to_client(
+    mut server_read: OwnedReadHalf,
+    mut client_write: OwnedWriteHalf,
+    state: Arc<PipeState>,
+    args: Arc<Args>,
+    stats: Arc<Stats>,
+    mut h2: H2Tracker,
+    mut recorder: Recorder,
+) -> CloseReason {
+    let client_addr: SocketAddr = state.client_addr;
+    let mut coalescer: Coalescer = Coalescer::new(args.coalesce, args.coalesce_bytes, Duration::from_millis(args.coalesce_interval_ms));
+    let mut buffer: Vec<u8> = vec![0; args.server_buffer_size as usize]; // Buffer for reading data.
+    let _account: BufferAccount = BufferAccount::new(buffer.capacity());
+    let mut stripper: ResponseStripper = ResponseStripper::new(args.strip_response_lines, args.strip_response_bytes);
+    let mut ftp: Option<FtpRewriter> = (args.mode == Mode::Ftp).then(|| FtpRewriter::new(state.local_addr.ip(), client_addr.ip(), state.target_addr.ip(), Arc::clone(&args), stats));
+    let keepalive_interval: Option<Duration> = args.client_keepalive_interval.map(Duration::from_secs);
+    let mut keepalive_at: Option<Instant> = keepalive_interval.map(|interval| Instant::now() + interval);
+
+    loop {
+        // While coalesced data is pending, also wake up when it is due to be flushed.
+        let result: io::Result<usize> = match coalescer.deadline() {
+            Some(deadline) => tokio::select! {
+                result = server_read.read(&mut buffer) => result,
+                _ = tokio::time::sleep_until(deadline) => {
+                    if let Err(e) = coalescer.flush(&mut client_write).await {
+                        log!(Error, "write_failed", { "client" => client_addr, "peer" => "client", "error" => e }, "Failed to write to client: {}", e);
+                        break CloseReason::ClientError;
+                    }
+                    continue;
+                }
+            },
+            // When idle, also wake up to send the client keepalive so NATs keep the mapping open.
+            None => match keepalive_at {
+                Some(at) => tokio::select! {
+                    result = server_read.read(&mut buffer) => result,
+                    _ = tokio::time::sleep_until(at) => {
+                        if let Err(e) = client_write.write_all(&args.client_keepalive_bytes).await {
+                            log!(Error, "write_failed", { "client" => client_addr, "peer" => "client", "error" => e }, "Failed to write to client: {}", e);
+                            break CloseReason::ClientError;
+                        }
+                        keepalive_at = keepalive_interval.map(|interval| Instant::now() + interval);
+                        continue;
+                    }
+                },
+                None => server_read.read(&mut buffer).await,
+            },
+        };
+
+        match result {
+            // End of stream: flush what is left and break the loop.
+            Ok(0) => {
+                let rest: Vec<u8> = ftp.as_mut().map(FtpRewriter::finish).unwrap_or_default();
+                let allowed: usize = state.budget.claim(rest.len());
+                if let Err(e) = coalescer.write(&mut client_write, &rest[..allowed]).await {
+                    log!(Error, "write_failed", { "client" => client_addr, "peer" => "client", "error" => e }, "Failed to write to client: {}", e);
+                    break CloseReason::ClientError;
+                }
+                recorder.record(&rest[..allowed]).await;
+                if let Err(e) = coalescer.flush(&mut client_write).await {
+                    log!(Error, "write_failed", { "client" => client_addr, "peer" => "client", "error" => e }, "Failed to write to client: {}", e);
+                    break CloseReason::ClientError;
+                }
+                break CloseReason::ServerEof;
+            }
+            // Read data from the server.
+            Ok(n) => {
+                // Strip the configured response prefix, if any is left.
+                let data: &[u8] = stripper.strip(&buffer[..n]);
+
+                // In FTP mode, hold back partial lines and point passive-mode replies at a relay.
+                let rewritten: Vec<u8>;
+                let data: &[u8] = match ftp.as_mut() {
+                    Some(ftp) => {
+                        rewritten = ftp.rewrite(data).await;
+                        &rewritten
+                    }
+                    None => data,
+                };
+                if data.is_empty() {
+                    continue;
+                }
+
+                // Forward the packet to the client, cut short if it runs over the byte limit.
+                let allowed: usize = state.budget.claim(data.len());
+                h2.observe(&data[..allowed]);
+                if let Err(e) = coalescer.write(&mut client_write, &data[..allowed]).await {
+                    log!(Error, "write_failed", { "client" => client_addr, "peer" => "client", "error" => e }, "Failed to write to client: {}", e);
+                    break CloseReason::ClientError;
+                }
+                state.flow.down(allowed);
+                state.first_response.get_or_init(Instant::now);
+                recorder.record(&data[..allowed]).await;
+                keepalive_at = keepalive_interval.map(|interval| Instant::now() + interval);
+                if allowed < data.len() {
+                    if let Err(e) = coalescer.flush(&mut client_write).await {
+                        log!(Error, "write_failed", { "client" => client_addr, "peer" => "client", "error" => e }, "Failed to write to client: {}", e);
+                        break CloseReason::ClientError;
+                    }
+                    break CloseReason::ByteLimit;
+                }
+            }
+            // If reading from the server fails, log the error and break the loop.
+            Err(e) => {
+                log!(Error, "read_failed", { "client" => client_addr, "peer" => "server", "error" => e }, "Failed to read from server: {}", e);
+                break CloseReason::ServerError;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn skipper_drops_the_first_packets_and_forwards_the_rest() {
+        let mut skipper: PacketSkipper = PacketSkipper::new(2, 0, 0);
+        assert_eq!(skipper.filter(b"GET / HTTP/1.1\r\n"), Skip::Dropped);
+        assert_eq!(skipper.filter(b"Host: x\r\n\r\n"), Skip::Dropped);
+        assert_eq!(skipper.filter(b"SSH-2.0-client"), Skip::Forward(b"SSH-2.0-client"));
+        assert_eq!(skipper.filter(b"more"), Skip::Forward(b"more"));
+    }
+
+    #[test]
+    fn skipper_without_skip_forwards_everything() {
+        let mut skipper: PacketSkipper = PacketSkipper::new(0, 0, 4);
+        assert_eq!(skipper.filter(b"longer than the limit"), Skip::Forward(b"longer than the limit"));
+    }
+
+    #[test]
+    fn skipper_enforces_the_handshake_byte_limit() {
+        let mut skipper: PacketSkipper = PacketSkipper::new(3, 0, 10);
+        assert_eq!(skipper.filter(b"1234"), Skip::Dropped);
+        assert_eq!(skipper.filter(b"123456"), Skip::Dropped);
+        assert_eq!(skipper.filter(b"1"), Skip::LimitExceeded);
+    }
+
+    #[test]
+    fn skipper_does_not_count_forwarded_bytes_against_the_limit() {
+        let mut skipper: PacketSkipper = PacketSkipper::new(1, 0, 4);
+        assert_eq!(skipper.filter(b"1234"), Skip::Dropped);
+        assert_eq!(skipper.filter(b"12345678"), Skip::Forward(b"12345678"));
+    }
+
+    /// Runs `chunks` through `skipper` and returns everything it forwards.
+    fn forwarded(skipper: &mut PacketSkipper, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::new();
+        for chunk in chunks {
+            if let Skip::Forward(data) = skipper.filter(chunk) {
+                output.extend_from_slice(data);
+            }
+        }
+        output
+    }
+
+    /// Splits `data` at `cuts` (taken modulo its length) into non-empty chunks, as reads would.
+    fn chunked(data: &[u8], cuts: &[usize]) -> Vec<Vec<u8>> {
+        let mut cuts: Vec<usize> = cuts.iter().filter(|_| !data.is_empty()).map(|cut| cut % data.len()).filter(|cut| *cut > 0).collect();
+        cuts.push(0);
+        cuts.push(data.len());
+        cuts.sort_unstable();
+        cuts.dedup();
+        cuts.windows(2).map(|pair| data[pair[0]..pair[1]].to_vec()).collect()
+    }
+
+    #[test]
+    fn skipper_drops_bytes_inside_a_packet() {
+        let mut skipper: PacketSkipper = PacketSkipper::new(1, 3, 0);
+        assert_eq!(skipper.filter(b"first"), Skip::Dropped);
+        assert_eq!(skipper.filter(b"ab"), Skip::Dropped);
+        assert_eq!(skipper.filter(b"cdef"), Skip::Forward(b"def"));
+        assert_eq!(skipper.filter(b"gh"), Skip::Forward(b"gh"));
+    }
+
+    proptest! {
+        #[test]
+        fn byte_skip_forwards_the_same_bytes_for_any_chunking(data in proptest::collection::vec(any::<u8>(), 0..512), cuts in proptest::collection::vec(any::<usize>(), 0..32), skip in 0usize..600) {
+            let mut skipper: PacketSkipper = PacketSkipper::new(0, skip, 0);
+            prop_assert_eq!(forwarded(&mut skipper, &chunked(&data, &cuts)), data[skip.min(data.len())..].to_vec());
+        }
+
+        /// `--skip` drops whole reads, so what it forwards is everything after the first `skip`
+        /// reads, wherever their boundaries fell; `--skip-bytes` is the boundary-independent option.
+        #[test]
+        fn packet_skip_forwards_everything_after_the_first_reads(data in proptest::collection::vec(any::<u8>(), 0..512), cuts in proptest::collection::vec(any::<usize>(), 0..32), skip in 0usize..40) {
+            let chunks: Vec<Vec<u8>> = chunked(&data, &cuts);
+            let mut skipper: PacketSkipper = PacketSkipper::new(skip, 0, 0);
+            prop_assert_eq!(forwarded(&mut skipper, &chunks), chunks.iter().skip(skip).flatten().copied().collect::<Vec<u8>>());
+        }
+    }
+
+    #[test]
+    fn stripper_drops_lines_split_across_reads() {
+        let mut stripper: ResponseStripper = ResponseStripper::new(2, 0);
+        assert_eq!(stripper.strip(b"HTTP/1.1 200"), b"");
+        assert_eq!(stripper.strip(b" OK\r\nServer: x\r"), b"");
+        assert_eq!(stripper.strip(b"\nbody"), b"body");
+        assert_eq!(stripper.strip(b"\nrest\n"), b"\nrest\n");
+    }
+
+    #[test]
+    fn stripper_drops_bytes_split_across_reads() {
+        let mut stripper: ResponseStripper = ResponseStripper::new(0, 5);
+        assert_eq!(stripper.strip(b"abc"), b"");
+        assert_eq!(stripper.strip(b"defg"), b"fg");
+        assert_eq!(stripper.strip(b"hij"), b"hij");
+    }
+
+    #[test]
+    fn stripper_drops_lines_before_bytes() {
+        let mut stripper: ResponseStripper = ResponseStripper::new(1, 2);
+        assert_eq!(stripper.strip(b"line\n"), b"");
+        assert_eq!(stripper.strip(b"x"), b"");
+        assert_eq!(stripper.strip(b"yz"), b"z");
+    }
+
+    #[test]
+    fn budget_without_limit_allows_everything() {
+        let budget: ByteBudget = ByteBudget::new(0);
+        assert_eq!(budget.claim(usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn budget_cuts_off_at_the_limit() {
+        let budget: ByteBudget = ByteBudget::new(10);
+        assert_eq!(budget.claim(4), 4);
+        assert_eq!(budget.claim(4), 4);
+        assert_eq!(budget.claim(4), 2);
+        assert_eq!(budget.claim(1), 0);
+    }
+
+    /// Builds a TLS handshake record holding `body`.
+    fn handshake_record(body: &[u8]) -> Vec<u8> {
+        let mut record: Vec<u8> = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        record.extend_from_slice(body);
+        record
+    }
+
+    #[test]
+    fn fragmenting_ignores_data_that_is_not_a_handshake() {
+        assert_eq!(fragment_client_hello(b"GET / HTTP/1.1\r\n", 4, false), None);
+        assert_eq!(fragment_client_hello(&[TLS_HANDSHAKE, 0x03], 4, false), None);
+        assert_eq!(fragment_client_hello(&handshake_record(b"hello"), 0, false), None);
+    }
+
+    #[test]
+    fn fragmenting_without_records_splits_the_bytes() {
+        let data: Vec<u8> = handshake_record(b"0123456789");
+        let pieces: Vec<Vec<u8>> = fragment_client_hello(&data, 4, false).unwrap();
+        assert_eq!(pieces.len(), 4);
+        assert!(pieces.iter().all(|piece| piece.len() <= 4));
+        assert_eq!(pieces.concat(), data);
+    }
+
+    #[test]
+    fn fragmenting_with_records_gives_each_piece_a_header() {
+        let mut data: Vec<u8> = handshake_record(b"0123456789");
+        data.extend_from_slice(b"after");
+        let pieces: Vec<Vec<u8>> = fragment_client_hello(&data, 4, true).unwrap();
+        assert_eq!(pieces, vec![handshake_record(b"0123"), handshake_record(b"4567"), handshake_record(b"89"), b"after".to_vec()]);
+    }
+
+    #[test]
+    fn fragmenting_with_records_needs_the_whole_record() {
+        let data: Vec<u8> = handshake_record(b"0123456789");
+        assert_eq!(fragment_client_hello(&data[..8], 4, true), None);
+    }
+}
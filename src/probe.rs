@@ -0,0 +1,132 @@
+//! The `probe` subcommand, which tests payloads and SNI values against a host.
+
+use crate::config::{unescape, ProbeArgs};
+use crate::wire::{put_length_prefixed, random_bytes};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Builds a TLS ClientHello record for `server_name`, offering TLS 1.3 and 1.2 with common suites.
+///
+/// The hello is only meant to provoke a ServerHello: the key share is random and the
+/// handshake is never completed.
+pub fn client_hello(server_name: &str) -> Vec<u8> {
+    let u16s = |values: &[u16]| -> Vec<u8> { values.iter().flat_map(|v| v.to_be_bytes()).collect() };
+    let mut extensions: Vec<u8> = Vec::new();
+    let mut extension = |id: u16, data: &[u8]| {
+        extensions.extend_from_slice(&id.to_be_bytes());
+        put_length_prefixed(&mut extensions, 2, data);
+    };
+
+    // server_name: a list holding one host_name entry.
+    let mut name: Vec<u8> = vec![0];
+    put_length_prefixed(&mut name, 2, server_name.as_bytes());
+    let mut names: Vec<u8> = Vec::new();
+    put_length_prefixed(&mut names, 2, &name);
+    extension(0, &names);
+
+    // supported_groups, ec_point_formats, signature_algorithms and supported_versions.
+    let mut groups: Vec<u8> = Vec::new();
+    put_length_prefixed(&mut groups, 2, &u16s(&[0x001d, 0x0017, 0x0018]));
+    extension(10, &groups);
+    extension(11, &[1, 0]);
+    let mut algorithms: Vec<u8> = Vec::new();
+    put_length_prefixed(&mut algorithms, 2, &u16s(&[0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601]));
+    extension(13, &algorithms);
+    let mut versions: Vec<u8> = Vec::new();
+    put_length_prefixed(&mut versions, 1, &u16s(&[0x0304, 0x0303]));
+    extension(43, &versions);
+
+    // key_share: one X25519 share, any 32 bytes being a valid public key.
+    let mut share: Vec<u8> = 0x001du16.to_be_bytes().to_vec();
+    put_length_prefixed(&mut share, 2, &random_bytes(32));
+    let mut shares: Vec<u8> = Vec::new();
+    put_length_prefixed(&mut shares, 2, &share);
+    extension(51, &shares);
+
+    let mut hello: Vec<u8> = vec![0x03, 0x03];
+    hello.extend_from_slice(&random_bytes(32));
+    put_length_prefixed(&mut hello, 1, &random_bytes(32));
+    put_length_prefixed(&mut hello, 2, &u16s(&[0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8]));
+    hello.extend_from_slice(&[1, 0]);
+    put_length_prefixed(&mut hello, 2, &extensions);
+
+    let mut handshake: Vec<u8> = vec![0x01];
+    put_length_prefixed(&mut handshake, 3, &hello);
+    let mut record: Vec<u8> = vec![0x16, 0x03, 0x01];
+    put_length_prefixed(&mut record, 2, &handshake);
+    record
+}
+
+/// Runs the `probe` subcommand and returns whether every requested step succeeded.
+pub async fn probe(args: ProbeArgs) -> Result<bool, Box<dyn std::error::Error>> {
+    let timeout: Duration = Duration::from_secs(args.timeout);
+    let mut stream: TcpStream = match tokio::time::timeout(timeout, TcpStream::connect(&args.address)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            log!(Error, "probe_connect_failed", { "address" => args.address, "error" => e }, "Failed to connect to {}: {}", args.address, e);
+            return Ok(false);
+        }
+        Err(_) => {
+            log!(Error, "probe_connect_failed", { "address" => args.address, "error" => "timed out" }, "Failed to connect to {}: timed out", args.address);
+            return Ok(false);
+        }
+    };
+    log!(Info, "probe_connected", { "address" => args.address }, "Connected to {}", args.address);
+
+    let mut buffer: Vec<u8> = vec![0; 16384];
+    let mut ok: bool = true;
+
+    // Send the payload and report the first line of whatever comes back.
+    if let Some(payload) = args.payload.as_deref() {
+        stream.write_all(&unescape(payload)?).await?;
+        match tokio::time::timeout(timeout, stream.read(&mut buffer)).await {
+            Ok(Ok(0)) => {
+                log!(Warn, "probe_payload_closed", {}, "Payload sent; the connection was closed without a reply");
+                ok = false;
+            }
+            Ok(Ok(n)) => {
+                let reply: String = String::from_utf8_lossy(&buffer[..n]).lines().next().unwrap_or_default().to_string();
+                log!(Info, "probe_payload_reply", { "reply" => reply, "bytes" => n }, "Payload sent; reply: {:?} ({} bytes)", reply, n);
+            }
+            Ok(Err(e)) => {
+                log!(Warn, "probe_payload_failed", { "error" => e }, "Payload sent; reading the reply failed: {}", e);
+                ok = false;
+            }
+            // Tunnel-style payloads often get no reply at all, which is not a failure in itself.
+            Err(_) => log!(Info, "probe_payload_silent", {}, "Payload sent; no reply within {}s", args.timeout),
+        }
+    }
+
+    // Start a TLS handshake and look at the first record the server answers with.
+    if let Some(sni) = args.sni.as_deref() {
+        stream.write_all(&client_hello(sni)).await?;
+        let mut header: [u8; 6] = [0; 6];
+        let result: io::Result<()> = match tokio::time::timeout(timeout, stream.read_exact(&mut header)).await {
+            Ok(result) => result.map(|_| ()),
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        };
+        match (result, header) {
+            (Ok(()), [0x16, _, _, _, _, 0x02]) => {
+                log!(Info, "probe_tls_ok", { "sni" => sni }, "TLS handshake with SNI {} got a ServerHello", sni);
+            }
+            (Ok(()), [0x15, _, _, _, _, _]) => {
+                // An alert's second byte is its description; it may need one more read, bounded like the others.
+                let description: u8 = tokio::time::timeout(timeout, stream.read_u8()).await.ok().and_then(Result::ok).unwrap_or(0);
+                log!(Warn, "probe_tls_alert", { "sni" => sni, "alert" => description }, "TLS handshake with SNI {} was answered with alert {}", sni, description);
+                ok = false;
+            }
+            (Ok(()), _) => {
+                log!(Warn, "probe_tls_unexpected", { "sni" => sni }, "TLS handshake with SNI {} got a reply that is not a ServerHello", sni);
+                ok = false;
+            }
+            (Err(e), _) => {
+                log!(Warn, "probe_tls_failed", { "sni" => sni, "error" => e }, "TLS handshake with SNI {} failed: {}", sni, e);
+                ok = false;
+            }
+        }
+    }
+
+    Ok(ok)
+}
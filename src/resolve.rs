@@ -0,0 +1,274 @@
+//! Resolving the target host, falling back to configured DNS servers when the system resolver fails.
+
+use crate::config::Args;
+use crate::discovery::{put_dns_name, read_dns_name};
+use crate::listener::Stats;
+use crate::wire::random_bytes;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+/// The DNS record type of IPv4 addresses.
+pub const DNS_TYPE_A: u16 = 1;
+
+/// The DNS record type of IPv6 addresses.
+pub const DNS_TYPE_AAAA: u16 = 28;
+
+/// The name the system resolver is counted under.
+pub const SYSTEM_RESOLVER: &str = "system";
+
+/// Builds a recursive query for `name` of record type `kind`.
+pub fn dns_query(id: u16, name: &str, kind: u16) -> Vec<u8> {
+    let mut query: Vec<u8> = Vec::with_capacity(32 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    put_dns_name(&mut query, name);
+    query.extend_from_slice(&kind.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query
+}
+
+/// Returns the addresses in the answer section of a response to query `id`, or `None` if it is not one.
+pub fn dns_answer_addresses(message: &[u8], id: u16) -> Option<Vec<IpAddr>> {
+    if message.len() < 12 || message[..2] != id.to_be_bytes() || message[2] & 0x80 == 0 {
+        return None;
+    }
+    let questions: u16 = u16::from_be_bytes([message[4], message[5]]);
+    let answers: u16 = u16::from_be_bytes([message[6], message[7]]);
+
+    let mut offset: usize = 12;
+    for _ in 0..questions {
+        offset = read_dns_name(message, offset)?.1 + 4;
+    }
+
+    // CNAMEs and other records in the chain are skipped; only the addresses matter.
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    for _ in 0..answers {
+        offset = read_dns_name(message, offset)?.1;
+        let header: &[u8] = message.get(offset..offset + 10)?;
+        let kind: u16 = u16::from_be_bytes([header[0], header[1]]);
+        let length: usize = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let data: &[u8] = message.get(offset + 10..offset + 10 + length)?;
+        match (kind, data.len()) {
+            (DNS_TYPE_A, 4) => addresses.push(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (DNS_TYPE_AAAA, 16) => addresses.push(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?))),
+            _ => {}
+        }
+        offset += 10 + length;
+    }
+    Some(addresses)
+}
+
+/// Asks the DNS server at `server` for the A and AAAA records of `host`.
+pub async fn query_dns_server(server: SocketAddr, host: &str, timeout: Duration) -> io::Result<Vec<IpAddr>> {
+    let bind: SocketAddr = if server.is_ipv4() { (Ipv4Addr::UNSPECIFIED, 0).into() } else { (Ipv6Addr::UNSPECIFIED, 0).into() };
+    let socket: UdpSocket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+
+    let ids: Vec<u8> = random_bytes(4);
+    let queries: [(u16, u16); 2] = [(u16::from_be_bytes([ids[0], ids[1]]), DNS_TYPE_A), (u16::from_be_bytes([ids[2], ids[3]]), DNS_TYPE_AAAA)];
+    for (id, kind) in queries {
+        socket.send(&dns_query(id, host, kind)).await?;
+    }
+
+    // Wait for both answers; stray datagrams that answer neither query are ignored.
+    // If only one answer arrives in time, its addresses are enough.
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    let mut pending: Vec<u16> = queries.iter().map(|(id, _)| *id).collect();
+    let mut buffer: [u8; 1500] = [0; 1500];
+    let deadline: Instant = Instant::now() + timeout;
+    while !pending.is_empty() {
+        let n: usize = match tokio::time::timeout_at(deadline, socket.recv(&mut buffer)).await {
+            Ok(received) => received?,
+            Err(_) if !addresses.is_empty() => break,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer from {}", server))),
+        };
+        if let Some(index) = pending.iter().position(|id| dns_answer_addresses(&buffer[..n], *id).is_some()) {
+            addresses.extend(dns_answer_addresses(&buffer[..n], pending.remove(index)).unwrap_or_default());
+        }
+    }
+
+    if addresses.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses for {}", server, host)));
+    }
+    Ok(addresses)
+}
+
+/// Resolves the target, reusing the cached addresses while they are fresh with `--dns-ttl-override`.
+pub async fn resolve_target(args: &Args, stats: &Stats, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let Some(ttl) = args.dns_ttl_override else {
+        return resolve_uncached(args, stats, host, port).await;
+    };
+
+    if let Some((expires, addrs)) = stats.target_cache.lock().unwrap().as_ref() {
+        if Instant::now() < *expires {
+            return Ok(addrs.clone());
+        }
+    }
+    let addrs: Vec<SocketAddr> = resolve_uncached(args, stats, host, port).await?;
+    *stats.target_cache.lock().unwrap() = Some((Instant::now() + Duration::from_secs(ttl), addrs.clone()));
+    Ok(addrs)
+}
+
+/// Drops the cached target addresses, so the next connection resolves the target again.
+///
+/// Called when none of the cached addresses accepted a connection, which is how a target
+/// that moved to a new address shows up.
+pub fn forget_target(stats: &Stats) {
+    stats.target_cache.lock().unwrap().take();
+}
+
+/// Resolves `host` through the system resolver, then through each `--dns-fallback` server in turn.
+///
+/// Every attempt is counted in `Stats::resolvers` under the resolver's name, and the last
+/// failure is returned if none of them produces an address.
+pub async fn resolve_uncached(args: &Args, stats: &Stats, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let timeout: Duration = Duration::from_secs(args.dns_timeout);
+
+    let system: io::Result<Vec<SocketAddr>> = match tokio::time::timeout(timeout, tokio::net::lookup_host((host, port))).await {
+        Ok(Ok(addrs)) => Ok(addrs.collect()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "system resolver timed out")),
+    };
+    let mut last_error: io::Error = match stats.count_resolution(SYSTEM_RESOLVER, system) {
+        Ok(addrs) => return Ok(addrs),
+        Err(e) => e,
+    };
+
+    for server in &args.dns_fallback {
+        log!(Warn, "dns_fallback", { "host" => host, "resolver" => *server, "error" => last_error }, "Resolving {} failed ({}); trying {}", host, last_error, server);
+        let result: io::Result<Vec<SocketAddr>> =
+            query_dns_server(*server, host, timeout).await.map(|ips| ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect());
+        match stats.count_resolution(&server.to_string(), result) {
+            Ok(addrs) => return Ok(addrs),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The DNS record type of aliases.
+    const DNS_TYPE_CNAME: u16 = 5;
+
+    /// Builds a response to query `id` for `name`, with the given raw answer records.
+    fn response(id: u16, name: &str, answers: &[Vec<u8>]) -> Vec<u8> {
+        let mut message: Vec<u8> = dns_query(id, name, DNS_TYPE_A);
+        message[2] |= 0x80;
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for answer in answers {
+            message.extend_from_slice(answer);
+        }
+        message
+    }
+
+    /// Builds an answer record whose owner is the name at `owner` (a compression pointer target).
+    fn record(owner: u16, kind: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut record: Vec<u8> = (0xc000 | owner).to_be_bytes().to_vec();
+        record.extend_from_slice(&kind.to_be_bytes());
+        record.extend_from_slice(&1u16.to_be_bytes());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn queries_ask_for_one_record() {
+        let query: Vec<u8> = dns_query(0x1234, "Example.com.", DNS_TYPE_AAAA);
+        assert_eq!(&query[..12], &[0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(&query[12..], b"\x07Example\x03com\x00\x00\x1c\x00\x01");
+        assert_eq!(read_dns_name(&query, 12), Some(("example.com".to_string(), 25)));
+    }
+
+    #[test]
+    fn answers_give_their_addresses() {
+        let message: Vec<u8> = response(7, "example.com", &[record(12, DNS_TYPE_A, 300, &[192, 0, 2, 1]), record(12, DNS_TYPE_AAAA, 300, &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])]);
+        assert_eq!(dns_answer_addresses(&message, 7), Some(vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()]));
+    }
+
+    #[test]
+    fn zero_ttl_answers_are_still_used() {
+        let message: Vec<u8> = response(7, "example.com", &[record(12, DNS_TYPE_A, 0, &[192, 0, 2, 1])]);
+        assert_eq!(dns_answer_addresses(&message, 7), Some(vec!["192.0.2.1".parse().unwrap()]));
+    }
+
+    #[test]
+    fn cname_chains_are_followed_to_the_addresses() {
+        // example.com -> www.example.net (written out) -> cdn.example.net (compressed against it) -> A.
+        let mut alias: Vec<u8> = Vec::new();
+        put_dns_name(&mut alias, "www.example.net");
+        let first: Vec<u8> = record(12, DNS_TYPE_CNAME, 60, &alias);
+        let alias_offset: u16 = (29 + first.len() - alias.len()) as u16;
+        let mut second_alias: Vec<u8> = b"\x03cdn".to_vec();
+        second_alias.extend_from_slice(&(0xc000 | (alias_offset + 4)).to_be_bytes());
+        let second: Vec<u8> = record(alias_offset, DNS_TYPE_CNAME, 60, &second_alias);
+        let second_alias_offset: u16 = (29 + first.len() + second.len() - second_alias.len()) as u16;
+        let address: Vec<u8> = record(second_alias_offset, DNS_TYPE_A, 60, &[198, 51, 100, 7]);
+        let message: Vec<u8> = response(9, "example.com", &[first, second, address]);
+
+        assert_eq!(read_dns_name(&message, second_alias_offset as usize).unwrap().0, "cdn.example.net");
+        assert_eq!(dns_answer_addresses(&message, 9), Some(vec!["198.51.100.7".parse().unwrap()]));
+    }
+
+    #[test]
+    fn responses_to_other_queries_are_ignored() {
+        let message: Vec<u8> = response(7, "example.com", &[record(12, DNS_TYPE_A, 300, &[192, 0, 2, 1])]);
+        assert_eq!(dns_answer_addresses(&message, 8), None);
+        assert_eq!(dns_answer_addresses(&dns_query(7, "example.com", DNS_TYPE_A), 7), None);
+        assert_eq!(dns_answer_addresses(&message[..11], 7), None);
+    }
+
+    #[test]
+    fn truncated_records_are_rejected() {
+        let message: Vec<u8> = response(7, "example.com", &[record(12, DNS_TYPE_A, 300, &[192, 0, 2, 1])]);
+        for cut in 30..message.len() {
+            assert_eq!(dns_answer_addresses(&message[..cut], 7), None, "cut at {}", cut);
+        }
+        // An address record whose RDATA has the wrong length is skipped rather than misread.
+        let odd: Vec<u8> = response(7, "example.com", &[record(12, DNS_TYPE_A, 300, &[192, 0, 2])]);
+        assert_eq!(dns_answer_addresses(&odd, 7), Some(vec![]));
+    }
+
+    #[test]
+    fn pointer_loops_terminate() {
+        // A record owner that points at itself, and two pointers that point at each other.
+        let mut looped: Vec<u8> = response(7, "example.com", &[]);
+        looped[6..8].copy_from_slice(&1u16.to_be_bytes());
+        let here: u16 = looped.len() as u16;
+        looped.extend_from_slice(&(0xc000 | here).to_be_bytes());
+        assert_eq!(dns_answer_addresses(&looped, 7), None);
+
+        let mut mutual: Vec<u8> = response(7, "example.com", &[]);
+        mutual[6..8].copy_from_slice(&1u16.to_be_bytes());
+        let here: u16 = mutual.len() as u16;
+        mutual.extend_from_slice(&(0xc000 | (here + 2)).to_be_bytes());
+        mutual.extend_from_slice(&(0xc000 | here).to_be_bytes());
+        assert_eq!(dns_answer_addresses(&mutual, 7), None);
+
+        assert_eq!(read_dns_name(&[0xc0, 0x00], 0), None);
+        assert_eq!(read_dns_name(&[0xc0, 0x10], 0), None);
+    }
+
+    #[test]
+    fn names_are_bounded_but_may_have_many_labels() {
+        let mut many: Vec<u8> = Vec::new();
+        put_dns_name(&mut many, &vec!["a"; 100].join("."));
+        assert_eq!(read_dns_name(&many, 0).map(|(name, end)| (name.len(), end)), Some((199, 201)));
+
+        let mut long: Vec<u8> = Vec::new();
+        put_dns_name(&mut long, &vec!["a"; 130].join("."));
+        assert_eq!(read_dns_name(&long, 0), None);
+
+        // Label types 0x40 and 0x80 are reserved.
+        assert_eq!(read_dns_name(&[0x41, b'a', 0], 0), None);
+    }
+}
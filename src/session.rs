@@ -0,0 +1,916 @@
+//! The lifetime of a single client connection, from accept to close.
+
+use crate::config::{Args, Mode, PayloadStage};
+use crate::flow::{unix_millis, FlowExporter};
+use crate::hook::run_hook;
+use crate::listener::{Stats, TargetGuard};
+use crate::memory::BufferAccount;
+use crate::pattern::Pattern;
+use crate::pipe::{self, H2Counters, H2Tracker, PacketSkipper, PipeState, Recorder, Skip};
+use crate::resolve::{forget_target, resolve_target};
+use crate::socks::socks5_connect;
+use crate::flow::FlowCounters;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest, Ready};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Limits the number of in-flight connect attempts per target address.
+///
+/// Each target gets its own semaphore, created lazily on first use, so a burst of
+/// clients cannot open an unbounded number of simultaneous handshakes against a backend.
+pub struct DialLimiter {
+    limit: usize,
+    targets: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl DialLimiter {
+    /// Creates a limiter allowing `limit` concurrent dials per target, or unlimited if `limit` is zero.
+    pub fn new(limit: usize) -> Self {
+        DialLimiter { limit, targets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Waits for a dial slot for `target`.
+    ///
+    /// The returned permit must be held for the duration of the connect attempt.
+    /// Returns `None` when no limit is configured.
+    pub async fn acquire(&self, target: &str) -> Option<OwnedSemaphorePermit> {
+        if self.limit == 0 {
+            return None;
+        }
+
+        // Look up (or create) the semaphore for this target without holding the lock across the await.
+        let semaphore: Arc<Semaphore> = {
+            let mut targets = self.targets.lock().unwrap();
+            Arc::clone(targets.entry(target.to_string()).or_insert_with(|| Arc::new(Semaphore::new(self.limit))))
+        };
+
+        // The semaphore is never closed, so acquiring can only fail if that invariant is broken.
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
+/// Why a proxied connection ended, as reported in the per-connection summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed its side of the connection.
+    ClientEof,
+    /// The target server closed its side of the connection.
+    ServerEof,
+    /// Reading from or writing to the client failed.
+    ClientError,
+    /// Reading from or writing to the target server failed.
+    ServerError,
+    /// The connection was refused by the proxy's own policy.
+    PolicyDenied,
+    /// The connection reached `--max-bytes-per-conn`.
+    ByteLimit,
+    /// Nothing was forwarded within `--handshake-timeout`.
+    HandshakeTimeout,
+    /// The connection was closed through the control socket.
+    Killed,
+}
+
+impl CloseReason {
+    /// Returns `true` if this reason ends the whole connection rather than just one direction.
+    pub fn closes_both_directions(self) -> bool {
+        matches!(self, CloseReason::PolicyDenied | CloseReason::ByteLimit)
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::ClientEof => "client-eof",
+            CloseReason::ServerEof => "server-eof",
+            CloseReason::ClientError => "client-error",
+            CloseReason::ServerError => "server-error",
+            CloseReason::PolicyDenied => "policy-denied",
+            CloseReason::ByteLimit => "byte-limit",
+            CloseReason::HandshakeTimeout => "handshake-timeout",
+            CloseReason::Killed => "killed",
+        })
+    }
+}
+
+/// The error a connection fails with when its handshake outlasts `--handshake-timeout`.
+#[derive(Debug)]
+pub struct HandshakeTimeout;
+
+impl fmt::Display for HandshakeTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("handshake timed out")
+    }
+}
+
+impl std::error::Error for HandshakeTimeout {}
+
+/// Builds the PROXY protocol v1 header for a client at `client` that connected to `local`.
+///
+/// Mixed address families cannot be expressed, so they are sent as `UNKNOWN`.
+pub fn proxy_protocol_header(client: SocketAddr, local: SocketAddr) -> String {
+    match (client, local) {
+        (SocketAddr::V4(c), SocketAddr::V4(l)) => format!("PROXY TCP4 {} {} {} {}\r\n", c.ip(), l.ip(), c.port(), l.port()),
+        (SocketAddr::V6(c), SocketAddr::V6(l)) => format!("PROXY TCP6 {} {} {} {}\r\n", c.ip(), l.ip(), c.port(), l.port()),
+        _ => "PROXY UNKNOWN\r\n".to_string(),
+    }
+}
+
+/// Reads the client's pre-auth line and returns `true` if it is `token` followed by `\n` or `\r\n`.
+///
+/// Exactly the line is read, so nothing the client sends after it is consumed.
+pub async fn read_preauth(client: &mut TcpStream, token: &str) -> io::Result<bool> {
+    let mut line: Vec<u8> = vec![0; token.len() + 1];
+    client.read_exact(&mut line).await?;
+    if line.last() == Some(&b'\r') {
+        line.push(client.read_u8().await?);
+    }
+    let expected: Vec<u8> = [token.as_bytes(), if line.len() > token.len() + 1 { b"\r\n" } else { b"\n" }].concat();
+
+    // Compare every byte so the time taken does not reveal how much of the token was right.
+    Ok(line.iter().zip(&expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0)
+}
+
+/// Aborts a spawned task when dropped, so a connection's forwarding tasks end with it
+/// even when the connection itself is stopped from outside.
+pub struct AbortOnDrop<T>(pub tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Finishes the bookkeeping for a connection that reached the forwarding phase: it feeds the
+/// histograms, exports the flow record and runs `--on-close`.
+///
+/// This happens on drop, so a connection stopped from the control socket mid-transfer is
+/// reported too, as `killed` unless `reason` was set by the normal close path.
+struct CloseReport {
+    args: Arc<Args>,
+    stats: Arc<Stats>,
+    flows: Arc<FlowExporter>,
+    state: Arc<PipeState>,
+    hook_env: Vec<(&'static str, String)>,
+    client_addr: SocketAddr,
+    target_addr: SocketAddr,
+    start_ms: u64,
+    started: Instant,
+    reason: CloseReason,
+}
+
+impl Drop for CloseReport {
+    fn drop(&mut self) {
+        // Feed the percentiles reported by the control socket and stats dumps.
+        let duration: Duration = self.started.elapsed();
+        let up_bytes: u64 = self.state.flow.up_bytes.load(Ordering::Relaxed);
+        let down_bytes: u64 = self.state.flow.down_bytes.load(Ordering::Relaxed);
+        self.stats.duration_ms.record(duration.as_millis() as u64);
+        self.stats.transfer_bytes.record(up_bytes + down_bytes);
+        self.stats.throughput_bps.record(((up_bytes + down_bytes) as f64 / duration.as_secs_f64().max(0.001)) as u64);
+
+        // Exporting is async, so it runs in its own task.
+        let flows: Arc<FlowExporter> = Arc::clone(&self.flows);
+        let state: Arc<PipeState> = Arc::clone(&self.state);
+        let (client_addr, target_addr, start_ms): (SocketAddr, SocketAddr, u64) = (self.client_addr, self.target_addr, self.start_ms);
+        tokio::spawn(async move {
+            if let Err(e) = flows.export(client_addr, target_addr, start_ms, &state.flow).await {
+                log!(Warn, "flow_export_failed", { "client" => client_addr, "error" => e }, "Failed to export flow record: {}", e);
+            }
+        });
+
+        if let Some(command) = self.args.on_close.as_deref() {
+            let mut env: Vec<(&'static str, String)> = std::mem::take(&mut self.hook_env);
+            env.push(("PROXY_STREAM_BYTES_UP", up_bytes.to_string()));
+            env.push(("PROXY_STREAM_BYTES_DOWN", down_bytes.to_string()));
+            env.push(("PROXY_STREAM_REASON", self.reason.to_string()));
+            run_hook("close", command, env);
+        }
+    }
+}
+
+/// Returns why a forwarding task ended, or `fallback` if it panicked, logging the panic.
+fn joined(result: Result<CloseReason, tokio::task::JoinError>, client_addr: SocketAddr, fallback: CloseReason) -> CloseReason {
+    result.unwrap_or_else(|e| {
+        log!(Error, "forwarding_failed", { "client" => client_addr, "error" => e.to_string() }, "Forwarding for {}:{} failed: {}", client_addr.ip(), client_addr.port(), e);
+        fallback
+    })
+}
+
+/// Runs a handshake step, failing with `HandshakeTimeout` if `deadline` passes first.
+async fn before<T, E: Into<Box<dyn std::error::Error>>>(deadline: Option<Instant>, step: impl Future<Output = Result<T, E>>) -> Result<T, Box<dyn std::error::Error>> {
+    match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, step).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(HandshakeTimeout.into()),
+        },
+        None => step.await.map_err(Into::into),
+    }
+}
+
+/// Completes at `deadline` if nothing has been forwarded by then; otherwise never completes.
+async fn handshake_expired(deadline: Option<Instant>, flow: &FlowCounters) {
+    if let Some(deadline) = deadline {
+        tokio::time::sleep_until(deadline).await;
+        if flow.up_bytes.load(Ordering::Relaxed) == 0 && flow.down_bytes.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Returns `true` if connecting to `addr` would lead straight back into this proxy.
+///
+/// That is the case for the listen port on a loopback or unspecified address, on the
+/// local address the client connected to, or for any configured self address.
+pub fn is_self_address(addr: &SocketAddr, local: &SocketAddr, args: &Args) -> bool {
+    let ip = addr.ip();
+    let listener: bool = addr.port() == args.listen_port && (ip.is_loopback() || ip.is_unspecified() || ip == local.ip());
+    listener || args.self_addresses.contains(addr)
+}
+
+/// Sets the DSCP bits of the IP TOS (IPv4) or traffic class (IPv6) field on a socket.
+#[cfg(unix)]
+pub fn set_dscp(stream: &TcpStream, dscp: u8) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // DSCP occupies the upper six bits; the lower two are left for ECN.
+    let value: libc::c_int = libc::c_int::from(dscp) << 2;
+    let (level, name) = match stream.local_addr()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TOS),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+    };
+
+    // SAFETY: the descriptor is valid for the lifetime of `stream`, and `value` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// DSCP marking is not supported on this platform.
+#[cfg(not(unix))]
+pub fn set_dscp(_stream: &TcpStream, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "DSCP marking is not available on this platform"))
+}
+
+/// Sets `TCP_MAXSEG` on a socket; on a listener it applies to the connections it accepts.
+#[cfg(unix)]
+pub fn set_mss<S: std::os::unix::io::AsRawFd>(socket: &S, mss: u16) -> io::Result<()> {
+    let value: libc::c_int = libc::c_int::from(mss);
+
+    // SAFETY: the descriptor is valid for the lifetime of `socket`, and `value` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// MSS clamping is not supported on this platform.
+#[cfg(not(unix))]
+pub fn set_mss<S>(_socket: &S, _mss: u16) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "MSS clamping is not available on this platform"))
+}
+
+/// Connects to the first of `addrs` that accepts, clamping the MSS first if `mss` is set.
+pub async fn connect_target(addrs: &[SocketAddr], mss: Option<u16>) -> io::Result<TcpStream> {
+    let Some(mss) = mss else {
+        return TcpStream::connect(addrs).await;
+    };
+
+    let mut last_error: io::Error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+    for addr in addrs {
+        let socket: TcpSocket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        set_mss(&socket, mss)?;
+        match socket.connect(*addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Returns the current local time as minutes since midnight.
+#[cfg(unix)]
+pub fn local_minute_of_day() -> io::Result<u16> {
+    // SAFETY: `time` accepts a null pointer, and `tm` is a valid, writable `tm` for the duration of `localtime_r`.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let now: libc::time_t = unsafe { libc::time(std::ptr::null_mut()) };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((tm.tm_hour * 60 + tm.tm_min) as u16)
+}
+
+/// Local time is not available on this platform.
+#[cfg(not(unix))]
+pub fn local_minute_of_day() -> io::Result<u16> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "local time is not available on this platform"))
+}
+
+/// The most client data an `expect` payload stage buffers while looking for its bytes.
+pub const MAX_STAGE_BYTES: usize = 64 * 1024;
+
+/// Runs the `--payload-stage` exchanges in order, starting with the already read `buffered` data.
+///
+/// Returns the client data left over after the last `expect` stage, or the reason to close
+/// the connection if the client hung up or sent too much without the expected bytes.
+async fn run_payload_stages(client: &mut TcpStream, stages: &[PayloadStage], mut buffered: Vec<u8>, buffer_size: usize) -> io::Result<Result<Vec<u8>, CloseReason>> {
+    let mut buffer: Vec<u8> = vec![0; buffer_size];
+    let mut account: BufferAccount = BufferAccount::new(buffer.capacity() + buffered.capacity());
+    for stage in stages {
+        match stage {
+            PayloadStage::Send(bytes) => client.write_all(bytes).await?,
+            PayloadStage::Expect(pattern) => loop {
+                if let Some(position) = buffered.windows(pattern.len()).position(|window| window == pattern.as_slice()) {
+                    buffered.drain(..position + pattern.len());
+                    break;
+                }
+                if buffered.len() > MAX_STAGE_BYTES {
+                    return Ok(Err(CloseReason::PolicyDenied));
+                }
+                let n: usize = match client.read(&mut buffer).await {
+                    Ok(0) => return Ok(Err(CloseReason::ClientEof)),
+                    Ok(n) => n,
+                    Err(_) => return Ok(Err(CloseReason::ClientError)),
+                };
+                buffered.extend_from_slice(&buffer[..n]);
+                account.set(buffer.capacity() + buffered.capacity());
+            },
+        }
+    }
+    Ok(Ok(buffered))
+}
+
+/// Peeks at what `server` has received so far without waiting, failing with `WouldBlock` if nothing has.
+fn peek_now(server: &TcpStream, buffer: &mut [u8]) -> io::Result<usize> {
+    #[cfg(unix)]
+    let socket: ManuallyDrop<std::net::TcpStream> = {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        // SAFETY: the descriptor stays owned by `server`; `ManuallyDrop` keeps this borrowed copy from closing it.
+        ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(server.as_raw_fd()) })
+    };
+    #[cfg(not(unix))]
+    let socket: ManuallyDrop<std::net::TcpStream> = {
+        use std::os::windows::io::{AsRawSocket, FromRawSocket};
+        // SAFETY: the socket stays owned by `server`; `ManuallyDrop` keeps this borrowed copy from closing it.
+        ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_socket(server.as_raw_socket()) })
+    };
+    socket.peek(buffer)
+}
+
+/// Waits up to `timeout` for the data `server` has sent to match `pattern`, without consuming it.
+///
+/// Returns `false` if the server closes, fills `buffer_size` bytes, sends something the
+/// pattern can no longer match or runs out of time without a match.
+async fn check_banner(server: &TcpStream, pattern: &Pattern, buffer_size: usize, timeout: Duration) -> io::Result<bool> {
+    let mut buffer: Vec<u8> = vec![0; buffer_size];
+    let _account: BufferAccount = BufferAccount::new(buffer.capacity());
+    let check = async {
+        let mut seen: usize = 0;
+        loop {
+            // Peeked data stays pending, so the socket only counts as readable again once more
+            // arrives or the server closes, which peeking cannot report while data is pending.
+            let ready: Ready = server.ready(Interest::READABLE).await?;
+            let n: usize = match server.try_io(Interest::READABLE, || match peek_now(server, &mut buffer)? {
+                n if n > 0 && n == seen && !ready.is_read_closed() => Err(io::ErrorKind::WouldBlock.into()),
+                n => Ok(n),
+            }) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            };
+            if n > 0 && pattern.is_match(&buffer[..n]) {
+                return Ok(true);
+            }
+            if n == 0 || n == buffer.len() || ready.is_read_closed() || !pattern.could_match(&buffer[..n]) {
+                return Ok(false);
+            }
+            seen = n;
+        }
+    };
+    tokio::time::timeout(timeout, check).await.unwrap_or(Ok(false))
+}
+
+/// The state of the generator behind `sample`; zero until it is first seeded.
+static SAMPLE_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the next number from a xorshift64 generator seeded once from the clock and the process id.
+fn next_random() -> u64 {
+    let mut state: u64 = SAMPLE_STATE.load(Ordering::Relaxed);
+    loop {
+        let mut next: u64 = if state == 0 {
+            let nanos: u64 = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64);
+            // Xorshift never leaves zero, so the low bit is forced on.
+            (nanos ^ (u64::from(std::process::id()) << 32)) | 1
+        } else {
+            state
+        };
+        next ^= next << 13;
+        next ^= next >> 7;
+        next ^= next << 17;
+        match SAMPLE_STATE.compare_exchange_weak(state, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next,
+            Err(current) => state = current,
+        }
+    }
+}
+
+/// Decides at random whether a session falls into the `percent` share picked for inspection.
+///
+/// Each session is an independent draw, so which sessions are picked does not follow the
+/// order clients arrive in.
+fn sample(percent: f64) -> bool {
+    ((next_random() >> 11) as f64 / (1u64 << 53) as f64) * 100.0 < percent
+}
+
+/// Handles an individual client connection.
+///
+/// This function manages the data transfer between the client and the target server.
+/// It splits both the client and server connections into read and write halves
+/// to allow concurrent reading from and writing to the connections.
+pub async fn handle_client(client: TcpStream, args: Arc<Args>, dials: Arc<DialLimiter>, stats: Arc<Stats>, flows: Arc<FlowExporter>) -> Result<(), Box<dyn std::error::Error>> {
+    // Get the client's address for logging purposes.
+    let client_addr = client.peer_addr()?;
+
+    // A handshake that runs out of time is a normal way for a connection to end, not a failure.
+    match serve_client(client, client_addr, args, dials, stats, flows).await {
+        Err(e) if e.is::<HandshakeTimeout>() => {
+            log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::HandshakeTimeout }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::HandshakeTimeout);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Serves a client connection; `handle_client` is the entry point.
+async fn serve_client(mut client: TcpStream, client_addr: SocketAddr, args: Arc<Args>, dials: Arc<DialLimiter>, stats: Arc<Stats>, flows: Arc<FlowExporter>) -> Result<(), Box<dyn std::error::Error>> {
+    let start_ms: u64 = unix_millis();
+    let started: Instant = Instant::now();
+    let deadline: Option<Instant> = args.handshake_timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+    log!(Info, "connection_received", { "client" => client_addr }, "Connection received from {}:{}", client_addr.ip(), client_addr.port());
+
+    // Outside the configured access hours, close before anything is sent or dialed.
+    // If the local time cannot be determined, fail closed.
+    if !args.allow_hours.is_empty() {
+        let allowed: bool = match local_minute_of_day() {
+            Ok(minute) => args.allow_hours.iter().any(|window| window.contains(minute)),
+            Err(e) => {
+                log!(Warn, "local_time_failed", { "error" => e }, "Failed to read local time for --allow-hours: {}", e);
+                false
+            }
+        };
+        if !allowed {
+            log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::PolicyDenied }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::PolicyDenied);
+            return Ok(());
+        }
+    }
+
+    // With a pre-auth token, the client must prove it knows the token before anything is sent or dialed.
+    if let Some(token) = args.preauth_token.as_deref() {
+        if !before(deadline, read_preauth(&mut client, token)).await? {
+            log!(Warn, "preauth_failed", { "client" => client_addr }, "Client {}:{} sent a wrong pre-auth token", client_addr.ip(), client_addr.port());
+            log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::PolicyDenied }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::PolicyDenied);
+            return Ok(());
+        }
+    }
+
+    // When injection is conditional or the client must match a pattern, read its first packet to decide.
+    let mut first_packet: Vec<u8> = Vec::new();
+    if !args.inject_if_prefix.is_empty() || args.expect_pattern.is_some() {
+        let mut buffer: Vec<u8> = vec![0; args.client_buffer_size as usize];
+        let _account: BufferAccount = BufferAccount::new(buffer.capacity());
+        let n: usize = before(deadline, client.read(&mut buffer)).await?;
+        first_packet.extend_from_slice(&buffer[..n]);
+    }
+
+    // Clients that don't look like the expected protocol are turned away before they cost an upstream connection.
+    if let Some(pattern) = &args.expect_pattern {
+        if !pattern.is_match(&first_packet) {
+            log!(Info, "expect_mismatch", { "client" => client_addr, "pattern" => pattern.to_string() }, "Client {}:{} did not match {}", client_addr.ip(), client_addr.port(), pattern);
+            if !args.reject_bytes.is_empty() {
+                before(deadline, client.write_all(&args.reject_bytes)).await?;
+            }
+            log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::PolicyDenied }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::PolicyDenied);
+            return Ok(());
+        }
+    }
+    // FTP clients talk to the server from the first byte, so nothing is injected or skipped for them.
+    let inject: bool = args.mode != Mode::Ftp && (args.inject_if_prefix.is_empty() || args.inject_if_prefix.iter().any(|prefix| first_packet.starts_with(prefix.as_bytes())));
+
+    // Send the configured fake response to the client.
+    // This can be useful for WebSocket or similar protocol upgrades.
+    if inject && !args.payload_bytes.is_empty() {
+        before(deadline, client.write_all(&args.payload_bytes)).await?;
+    }
+
+    // Multi-step handshakes continue from here; whatever the client sends past the last expected bytes is its first packet.
+    if inject && !args.payload_stages.is_empty() {
+        match before(deadline, run_payload_stages(&mut client, &args.payload_stages, std::mem::take(&mut first_packet), args.client_buffer_size as usize)).await? {
+            Ok(rest) => first_packet = rest,
+            Err(reason) => {
+                if reason == CloseReason::PolicyDenied {
+                    log!(Warn, "payload_stage_limit", { "client" => client_addr, "limit" => MAX_STAGE_BYTES }, "Client {}:{} sent more than {} bytes without the expected stage data", client_addr.ip(), client_addr.port(), MAX_STAGE_BYTES);
+                }
+                log!(Info, "connection_closed", { "client" => client_addr, "reason" => reason }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), reason);
+                return Ok(());
+            }
+        }
+    }
+
+    // In echo mode there is no upstream to dial; the client's data comes straight back.
+    if args.mode == Mode::Echo {
+        let skipper: PacketSkipper = PacketSkipper::new(if inject { args.skip } else { 0 }, if inject { args.skip_bytes } else { 0 }, args.max_handshake_bytes);
+        let reason: CloseReason = echo_client(client, first_packet, skipper, args.client_buffer_size as usize, client_addr).await;
+        log!(Info, "connection_closed", { "client" => client_addr, "reason" => reason }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), reason);
+        return Ok(());
+    }
+
+    // Establish a connection to the target server, holding a dial slot for the duration of the attempt.
+    // Resolve the target first and refuse to dial ourselves, which would otherwise loop until fds run out.
+    let target: String = format!("{}:{}", args.target_host, args.target_port);
+    let local_addr: SocketAddr = client.local_addr()?;
+    // Through a SOCKS server, the target is resolved at the far end so no lookup leaks from here.
+    let resolving: Instant = Instant::now();
+    let mut target_addrs: Vec<SocketAddr> = match args.upstream_socks5 {
+        Some(socks) => vec![socks],
+        None => before(deadline, resolve_target(&args, &stats, &args.target_host, args.target_port)).await?,
+    };
+    let resolved: Instant = Instant::now();
+    if let Some(addr) = target_addrs.iter().find(|addr| is_self_address(addr, &local_addr, &args)) {
+        log!(Warn, "self_target_refused", { "client" => client_addr, "target" => target, "addr" => *addr }, "Refusing to connect to {} ({}): it is this proxy's own address", target, addr);
+        log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::PolicyDenied }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::PolicyDenied);
+        return Ok(());
+    }
+
+    // Drained addresses are being taken out for maintenance, so new connections never go there.
+    target_addrs.retain(|addr| !stats.draining.lock().unwrap().contains(addr));
+    if target_addrs.is_empty() {
+        log!(Warn, "targets_draining", { "client" => client_addr, "target" => target }, "Every address of {} is draining", target);
+        log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::PolicyDenied }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::PolicyDenied);
+        return Ok(());
+    }
+
+    // Addresses that recently sent the wrong banner are only tried once the others have failed.
+    target_addrs.sort_by_key(|addr| stats.is_unhealthy(addr));
+
+    let mut server: TcpStream = before(deadline, async {
+        let _permit: Option<OwnedSemaphorePermit> = dials.acquire(&target).await;
+        let mut server: TcpStream = connect_target(&target_addrs, args.mss_clamp).await.inspect_err(|_| forget_target(&stats))?;
+        if args.upstream_socks5.is_some() {
+            socks5_connect(&mut server, &args.target_host, args.target_port).await?;
+        }
+        Ok::<TcpStream, io::Error>(server)
+    })
+    .await?;
+
+    let connected: Instant = Instant::now();
+    stats.connect_ms.record(connected.duration_since(resolved).as_millis() as u64);
+
+    // ClientHello fragments only leave as separate segments if Nagle's algorithm is off.
+    if args.hello_fragment_size > 0 {
+        server.set_nodelay(true)?;
+    }
+
+    // Tell the target who the client really is before any of the client's data arrives.
+    if args.proxy_protocol {
+        before(deadline, server.write_all(proxy_protocol_header(client_addr, local_addr).as_bytes())).await?;
+    }
+
+    // Count the connection against its target address so a drain knows when it is done.
+    let _target_guard: TargetGuard = TargetGuard::new(Arc::clone(&stats), server.peer_addr()?);
+
+    // A target that doesn't greet us the way it should is probably the wrong backend.
+    if let Some(pattern) = &args.expect_banner {
+        let timeout: Duration = Duration::from_secs(args.banner_timeout);
+        if !before(deadline, check_banner(&server, pattern, args.server_buffer_size as usize, timeout)).await? {
+            let addr: SocketAddr = server.peer_addr()?;
+            stats.unhealthy.lock().unwrap().insert(addr, Instant::now() + Duration::from_secs(args.banner_penalty));
+            log!(Warn, "banner_mismatch", { "client" => client_addr, "target" => target, "addr" => addr, "pattern" => pattern.to_string() }, "Target {} ({}) did not send a banner matching {}", target, addr, pattern);
+            log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::ServerError }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::ServerError);
+            return Ok(());
+        }
+    }
+
+    // Mark both legs of the connection so routers can prioritise tunnel traffic.
+    if let Some(dscp) = args.dscp {
+        for stream in [&client, &server] {
+            if let Err(e) = set_dscp(stream, dscp) {
+                log!(Warn, "dscp_failed", { "dscp" => dscp, "error" => e }, "Failed to set DSCP {}: {}", dscp, e);
+            }
+        }
+    }
+
+    // With `--h2-metrics`, both directions feed the frames they forward into shared counters.
+    let h2: Arc<H2Counters> = Arc::new(H2Counters::default());
+    let mut client_h2: H2Tracker = H2Tracker::new(args.h2_metrics, true, Arc::clone(&h2));
+    let server_h2: H2Tracker = H2Tracker::new(args.h2_metrics, false, Arc::clone(&h2));
+
+    // Both directions draw from the same byte allowance and flow counters.
+    let target_addr: SocketAddr = server.peer_addr()?;
+    let state: Arc<PipeState> = Arc::new(PipeState::new(client_addr, local_addr, target_addr, args.max_bytes_per_conn, args.hello_fragment_size > 0));
+
+    // Clients that did not get the payload are passed through untouched, so nothing is skipped for them.
+    // A packet that was already read counts towards the skip like any other.
+    let mut skipper: PacketSkipper = PacketSkipper::new(if inject { args.skip } else { 0 }, if inject { args.skip_bytes } else { 0 }, args.max_handshake_bytes);
+    // With `--inspect-sample`, only the sampled sessions are recorded and timed; the rest skip it.
+    let sampled: bool = args.inspect_sample.is_some_and(sample);
+    let record_dir: Option<&Path> = args.record_dir.as_deref().filter(|_| args.inspect_sample.is_none() || sampled);
+    let name: String = format!("{}-{}-{}", start_ms, client_addr.ip(), client_addr.port());
+    let mut up_recorder: Recorder = Recorder::create(record_dir, format!("{}.up", name)).await;
+    let down_recorder: Recorder = Recorder::create(record_dir, format!("{}.down", name)).await;
+    if !first_packet.is_empty() {
+        match skipper.filter(&first_packet) {
+            Skip::Forward(data) => {
+                let allowed: usize = state.budget.claim(data.len());
+                client_h2.observe(&data[..allowed]);
+                match state.take_hello().then(|| pipe::fragment_client_hello(&data[..allowed], args.hello_fragment_size, args.hello_fragment_records)).flatten() {
+                    Some(pieces) => before(deadline, pipe::write_fragments(&mut server, &pieces, Duration::from_millis(args.hello_fragment_delay_ms))).await?,
+                    None => before(deadline, server.write_all(&data[..allowed])).await?,
+                }
+                state.flow.up(allowed);
+                up_recorder.record(&data[..allowed]).await;
+            }
+            Skip::Dropped => {}
+            Skip::LimitExceeded => {
+                log!(Warn, "handshake_limit", { "client" => client_addr, "limit" => args.max_handshake_bytes }, "Client {}:{} sent more than {} handshake bytes", client_addr.ip(), client_addr.port(), args.max_handshake_bytes);
+                log!(Info, "connection_closed", { "client" => client_addr, "reason" => CloseReason::PolicyDenied }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), CloseReason::PolicyDenied);
+                return Ok(());
+            }
+        }
+    }
+
+    // Split the client and server connections into read and write halves
+    // to allow simultaneous reading and writing, and forward each direction in its own task.
+    // Hooks learn which client reached which target.
+    let hook_env: Vec<(&'static str, String)> = vec![
+        ("PROXY_STREAM_CLIENT_IP", client_addr.ip().to_string()),
+        ("PROXY_STREAM_CLIENT_PORT", client_addr.port().to_string()),
+        ("PROXY_STREAM_TARGET", target.clone()),
+        ("PROXY_STREAM_TARGET_ADDR", target_addr.to_string()),
+    ];
+    if let Some(command) = args.on_connect.as_deref() {
+        run_hook("connect", command, hook_env.clone());
+    }
+    let mut report: CloseReport = CloseReport {
+        args: Arc::clone(&args),
+        stats: Arc::clone(&stats),
+        flows,
+        state: Arc::clone(&state),
+        hook_env,
+        client_addr,
+        target_addr,
+        start_ms,
+        started,
+        reason: CloseReason::Killed,
+    };
+
+    let (client_read, client_write) = client.into_split();
+    let (server_read, server_write) = server.into_split();
+    let mut client_to_server: AbortOnDrop<CloseReason> =
+        AbortOnDrop(tokio::spawn(pipe::client_to_server(client_read, server_write, Arc::clone(&state), Arc::clone(&args), skipper, client_h2, up_recorder)));
+    let mut server_to_client: AbortOnDrop<CloseReason> =
+        AbortOnDrop(tokio::spawn(pipe::server_to_client(server_read, client_write, Arc::clone(&state), Arc::clone(&args), Arc::clone(&stats), server_h2, down_recorder)));
+
+    // The direction that finishes first determines why the connection closed;
+    // then wait for the other direction to wind down as well. Policy and byte limits
+    // end the whole connection, so the other direction is stopped instead.
+    // A handshake that has not forwarded anything by its deadline stops both.
+    let reason: CloseReason = tokio::select! {
+        _ = handshake_expired(deadline, &state.flow) => {
+            client_to_server.0.abort();
+            server_to_client.0.abort();
+            CloseReason::HandshakeTimeout
+        }
+        reason = &mut client_to_server.0 => {
+            let reason: CloseReason = joined(reason, client_addr, CloseReason::ClientError);
+            if reason.closes_both_directions() {
+                server_to_client.0.abort();
+            } else {
+                joined((&mut server_to_client.0).await, client_addr, CloseReason::ServerError);
+            }
+            reason
+        }
+        reason = &mut server_to_client.0 => {
+            let reason: CloseReason = joined(reason, client_addr, CloseReason::ServerError);
+            if reason.closes_both_directions() {
+                client_to_server.0.abort();
+            } else {
+                joined((&mut client_to_server.0).await, client_addr, CloseReason::ClientError);
+            }
+            reason
+        }
+    };
+    report.reason = reason;
+
+    if sampled {
+        let millis = |from: Instant, to: Instant| to.duration_since(from).as_millis() as u64;
+        let resolve_ms: u64 = millis(resolving, resolved);
+        let connect_ms: u64 = millis(resolved, connected);
+        let first_response_ms: Option<u64> = state.first_response.get().map(|at| millis(started, *at));
+        let duration_ms: u64 = millis(started, Instant::now());
+        let up_bytes: u64 = state.flow.up_bytes.load(Ordering::Relaxed);
+        let down_bytes: u64 = state.flow.down_bytes.load(Ordering::Relaxed);
+        log!(
+            Info,
+            "inspect_timing",
+            {
+                "client" => client_addr,
+                "recording" => name,
+                "resolve_ms" => resolve_ms,
+                "connect_ms" => connect_ms,
+                "first_response_ms" => first_response_ms,
+                "duration_ms" => duration_ms,
+                "up_bytes" => up_bytes,
+                "down_bytes" => down_bytes,
+            },
+            "Timing for {}:{} ({}): resolve {} ms, connect {} ms, first response {}, {} ms in all, {} bytes up, {} bytes down",
+            client_addr.ip(),
+            client_addr.port(),
+            name,
+            resolve_ms,
+            connect_ms,
+            first_response_ms.map_or("never".to_string(), |ms| format!("after {} ms", ms)),
+            duration_ms,
+            up_bytes,
+            down_bytes
+        );
+    }
+
+    // Report HTTP/2 stream activity for connections that turned out to speak it.
+    if h2.detected.load(Ordering::Relaxed) {
+        let streams: u64 = h2.streams.load(Ordering::Relaxed);
+        let client_resets: u64 = h2.client_resets.load(Ordering::Relaxed);
+        let server_resets: u64 = h2.server_resets.load(Ordering::Relaxed);
+        let reset_rate: f64 = if streams == 0 { 0.0 } else { (client_resets + server_resets) as f64 * 100.0 / streams as f64 };
+        stats.h2_streams.fetch_add(streams, Ordering::Relaxed);
+        stats.h2_resets.fetch_add(client_resets + server_resets, Ordering::Relaxed);
+        log!(
+            Info,
+            "h2_summary",
+            {
+                "client" => client_addr,
+                "streams" => streams,
+                "client_resets" => client_resets,
+                "server_resets" => server_resets,
+                "reset_rate" => reset_rate,
+            },
+            "HTTP/2 streams for {}:{}: {} opened, {} reset by client, {} reset by server ({:.1}% reset)",
+            client_addr.ip(),
+            client_addr.port(),
+            streams,
+            client_resets,
+            server_resets,
+            reset_rate
+        );
+    }
+
+    // Log the termination of the connection.
+    log!(Info, "connection_closed", { "client" => client_addr, "reason" => reason }, "Connection terminated for {}:{} (reason: {})", client_addr.ip(), client_addr.port(), reason);
+
+    // Return Ok to indicate the connection was handled successfully.
+    Ok(())
+}
+
+/// Serves a client in echo mode.
+///
+/// Packets (including an already read `first_packet`) go through `skipper` just as they
+/// would before forwarding, and everything it lets through is written back to the client.
+pub async fn echo_client(mut client: TcpStream, first_packet: Vec<u8>, mut skipper: PacketSkipper, buffer_size: usize, client_addr: SocketAddr) -> CloseReason {
+    let mut buffer: Vec<u8> = vec![0; buffer_size];
+    let mut account: BufferAccount = BufferAccount::new(buffer.capacity());
+    let mut pending: Option<usize> = (!first_packet.is_empty()).then_some(first_packet.len());
+    if let Some(n) = pending {
+        buffer.resize(buffer_size.max(n), 0);
+        buffer[..n].copy_from_slice(&first_packet);
+        account.set(buffer.capacity());
+    }
+
+    loop {
+        let n: usize = match pending.take() {
+            Some(n) => n,
+            None => match client.read(&mut buffer).await {
+                Ok(0) => break CloseReason::ClientEof,
+                Ok(n) => n,
+                Err(e) => {
+                    log!(Error, "read_failed", { "client" => client_addr, "peer" => "client", "error" => e }, "Failed to read from client: {}", e);
+                    break CloseReason::ClientError;
+                }
+            },
+        };
+
+        match skipper.filter(&buffer[..n]) {
+            Skip::Forward(data) => {
+                if let Err(e) = client.write_all(data).await {
+                    log!(Error, "write_failed", { "client" => client_addr, "peer" => "client", "error" => e }, "Failed to write to client: {}", e);
+                    break CloseReason::ClientError;
+                }
+            }
+            Skip::Dropped => {}
+            Skip::LimitExceeded => {
+                log!(Warn, "handshake_limit", { "client" => client_addr, "limit" => skipper.max_dropped() }, "Client {}:{} sent more than {} handshake bytes", client_addr.ip(), client_addr.port(), skipper.max_dropped());
+                break CloseReason::PolicyDenied;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::parse_pattern;
+    use tokio::net::TcpListener;
+
+    /// Connects to a server that sends `banner` and then closes if `close` is set, and checks it against `pattern`.
+    async fn banner_check(banner: &'static [u8], close: bool, pattern: &str) -> (bool, Duration) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            server.write_all(banner).await.unwrap();
+            if !close {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+        });
+        let server: TcpStream = TcpStream::connect(addr).await.unwrap();
+        let started: std::time::Instant = std::time::Instant::now();
+        let matched: bool = check_banner(&server, &parse_pattern(pattern).unwrap(), 4096, Duration::from_secs(2)).await.unwrap();
+        (matched, started.elapsed())
+    }
+
+    #[tokio::test]
+    async fn a_matching_banner_passes() {
+        assert!(banner_check(b"SSH-2.0-OpenSSH_9.6\r\n", false, "^SSH-2\\.0-").await.0);
+        assert!(banner_check(b"SSH-2.0-OpenSSH_9.6\r\n", true, "^SSH-2\\.0-").await.0);
+    }
+
+    #[tokio::test]
+    async fn a_partial_banner_followed_by_a_close_fails_at_once() {
+        let (matched, elapsed) = banner_check(b"SSH-2", true, "^SSH-2\\.0-").await;
+        assert!(!matched);
+        assert!(elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn a_partial_banner_waits_for_the_timeout() {
+        let (matched, elapsed) = banner_check(b"SSH-2", false, "^SSH-2\\.0-").await;
+        assert!(!matched);
+        assert!(elapsed >= Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn an_empty_close_fails_at_once() {
+        let (matched, elapsed) = banner_check(b"", true, "^SSH-").await;
+        assert!(!matched);
+        assert!(elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn sample_picks_about_the_requested_share() {
+        const DRAWS: usize = 100_000;
+        for percent in [1.0, 10.0, 50.0] {
+            let picked: usize = (0..DRAWS).filter(|_| sample(percent)).count();
+            let expected: f64 = DRAWS as f64 * percent / 100.0;
+            // Six standard deviations of the binomial distribution.
+            let tolerance: f64 = 6.0 * (expected * (1.0 - percent / 100.0)).sqrt();
+            assert!((picked as f64 - expected).abs() < tolerance, "{}%: picked {} of {}", percent, picked, DRAWS);
+        }
+        assert!((0..1000).all(|_| sample(100.0)));
+        assert!((0..1000).all(|_| !sample(0.0)));
+    }
+
+    #[test]
+    fn sample_does_not_follow_a_fixed_stride() {
+        // An every-Nth picker would always leave exactly 9 sessions between picks at 10%.
+        let gaps: Vec<usize> = (0..10_000).filter(|_| sample(10.0)).collect::<Vec<usize>>().windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert!(gaps.iter().any(|gap| *gap != gaps[0]));
+    }
+}
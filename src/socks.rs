@@ -0,0 +1,93 @@
+//! The client side of SOCKS5 `CONNECT`, for reaching the target through `--upstream-socks5`.
+//!
+//! Host names are passed to the SOCKS server as they are (the `socks5h` behaviour), so the
+//! target is never resolved locally.
+
+use std::io;
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The SOCKS protocol version this client speaks.
+const SOCKS_VERSION: u8 = 5;
+
+/// The `CONNECT` command.
+const SOCKS_CMD_CONNECT: u8 = 1;
+
+/// The "no authentication required" method.
+const SOCKS_AUTH_NONE: u8 = 0;
+
+/// Address types in requests and replies.
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+
+/// Describes a SOCKS5 reply code.
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Asks the SOCKS5 server on `stream` to connect to `host:port`, without authentication.
+///
+/// On success the stream carries the connection to the target.
+pub async fn socks5_connect<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, host: &str, port: u16) -> io::Result<()> {
+    let protocol_error = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    stream.write_all(&[SOCKS_VERSION, 1, SOCKS_AUTH_NONE]).await?;
+    let mut choice: [u8; 2] = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(protocol_error(format!("upstream is not a SOCKS5 server (version {})", choice[0])));
+    }
+    if choice[1] != SOCKS_AUTH_NONE {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 server requires authentication"));
+    }
+
+    let mut request: Vec<u8> = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS_ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS_ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let name: u8 = u8::try_from(host.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "host name is too long for SOCKS5"))?;
+            request.push(SOCKS_ATYP_DOMAIN);
+            request.push(name);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // The reply carries the server's bound address, which is read and discarded.
+    let mut reply: [u8; 4] = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(protocol_error(format!("malformed SOCKS5 reply (version {})", reply[0])));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("SOCKS5 server could not connect to {}:{}: {}", host, port, reply_message(reply[1]))));
+    }
+    let address_len: usize = match reply[3] {
+        SOCKS_ATYP_IPV4 => 4,
+        SOCKS_ATYP_IPV6 => 16,
+        SOCKS_ATYP_DOMAIN => stream.read_u8().await? as usize,
+        other => return Err(protocol_error(format!("unknown address type {} in SOCKS5 reply", other))),
+    };
+    let mut bound: Vec<u8> = vec![0; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
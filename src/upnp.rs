@@ -0,0 +1,337 @@
+//! UPnP Internet Gateway Device port mapping for `--upnp`: the router is found with an SSDP
+//! search and asked over SOAP to forward the listen port to this host.
+
+use crate::config::Args;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::Instant;
+
+/// The SSDP multicast group.
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+/// The SSDP port.
+const SSDP_PORT: u16 = 1900;
+
+/// The device type searched for.
+const IGD_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// The services that can map ports, most preferred first.
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// The lease, in seconds, requested for the listener's port mapping; it is renewed at half that.
+const UPNP_LEASE: u32 = 3600;
+
+/// How long to wait for SSDP replies and for each HTTP exchange with the router.
+const UPNP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The largest HTTP response read from the router.
+const MAX_UPNP_RESPONSE: u64 = 256 * 1024;
+
+/// The UPnP error code of a router that only accepts mappings without a lease.
+const ONLY_PERMANENT_LEASES: u32 = 725;
+
+/// An `http://` URL split into the address to connect to and the request path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HttpUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+/// Parses an `http://host[:port][/path]` URL.
+fn parse_http_url(url: &str) -> Option<HttpUrl> {
+    let rest: &str = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    let (host, port): (&str, u16) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.ends_with(']') => (host, port.parse().ok()?),
+        _ => (authority, 80),
+    };
+    let host: &str = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    Some(HttpUrl { host: host.to_string(), port, path: path.to_string() })
+}
+
+/// Resolves `reference` (absolute, host-relative or path-relative) against `base`.
+fn join_url(base: &HttpUrl, reference: &str) -> Option<HttpUrl> {
+    if reference.starts_with("http://") {
+        return parse_http_url(reference);
+    }
+    let path: String = if reference.starts_with('/') {
+        reference.to_string()
+    } else {
+        format!("{}{}", &base.path[..=base.path.rfind('/')?], reference)
+    };
+    Some(HttpUrl { host: base.host.clone(), port: base.port, path })
+}
+
+/// Returns the `LOCATION` header of an SSDP search response.
+fn ssdp_location(response: &[u8]) -> Option<String> {
+    let text: &str = std::str::from_utf8(response).ok()?;
+    if !text.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+    text.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+    })
+}
+
+/// Returns the trimmed text of the first element named `name` in `xml`, ignoring namespace prefixes.
+fn xml_text<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest: &str = xml;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        let close: usize = rest.find('>')?;
+        let tag: &str = rest[..close].split_whitespace().next().unwrap_or("");
+        rest = &rest[close + 1..];
+        if !tag.starts_with('/') && tag.rsplit(':').next() == Some(name) {
+            return Some(rest[..rest.find('<')?].trim());
+        }
+    }
+    None
+}
+
+/// Returns the type and control URL of the most preferred port-mapping service in a device description.
+fn find_wan_service(description: &str) -> Option<(&'static str, &str)> {
+    let services: Vec<&str> = description.split("<service>").skip(1).collect();
+    WAN_SERVICES.iter().find_map(|kind| {
+        let service: &&str = services.iter().find(|service| xml_text(service, "serviceType") == Some(*kind))?;
+        Some((*kind, xml_text(service, "controlURL")?))
+    })
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body.
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded: Vec<u8> = Vec::new();
+    loop {
+        let line_end: usize = body.windows(2).position(|pair| pair == b"\r\n")?;
+        let size: &str = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size: usize = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+/// Splits an HTTP response into its status code and (de-chunked) body.
+fn parse_http_response(response: &[u8]) -> Option<(u16, Vec<u8>)> {
+    let split: usize = response.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head: &str = std::str::from_utf8(&response[..split]).ok()?;
+    let status: u16 = head.lines().next()?.split_whitespace().nth(1)?.parse().ok()?;
+    let chunked: bool = head.lines().skip(1).any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| name.trim().eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked"))
+    });
+    let body: &[u8] = &response[split + 4..];
+    Some((status, if chunked { decode_chunked(body)? } else { body.to_vec() }))
+}
+
+/// Sends one HTTP request to `url` and returns the response's status code and body.
+async fn http_exchange(url: &HttpUrl, method: &str, headers: &str, body: &str) -> io::Result<(u16, String)> {
+    let exchange = async {
+        let mut stream: TcpStream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+        let host: String = if url.host.contains(':') { format!("[{}]:{}", url.host, url.port) } else { format!("{}:{}", url.host, url.port) };
+        let request: String =
+            format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n{}\r\n{}", method, url.path, host, body.len(), headers, body);
+        stream.write_all(request.as_bytes()).await?;
+        let mut response: Vec<u8> = Vec::new();
+        stream.take(MAX_UPNP_RESPONSE).read_to_end(&mut response).await?;
+        Ok::<Vec<u8>, io::Error>(response)
+    };
+    let response: Vec<u8> = tokio::time::timeout(UPNP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("no HTTP response from {}:{}", url.host, url.port)))??;
+    let (status, body) = parse_http_response(&response).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response from the UPnP gateway"))?;
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+/// A UPnP error the router answered a SOAP action with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UpnpFault {
+    code: u32,
+    description: String,
+}
+
+impl fmt::Display for UpnpFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UPnP error {} ({})", self.code, self.description)
+    }
+}
+
+/// Calls the SOAP `action` of `service` at `control`, returning the response body or the router's fault.
+async fn soap_call(control: &HttpUrl, service: &str, action: &str, arguments: &[(&str, String)]) -> io::Result<Result<String, UpnpFault>> {
+    let arguments: String = arguments.iter().map(|(name, value)| format!("<{0}>{1}</{0}>", name, value)).collect();
+    let body: String = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>\r\n",
+        action, service, arguments
+    );
+    let headers: String = format!("Content-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\n", service, action);
+    let (status, response) = http_exchange(control, "POST", &headers, &body).await?;
+    if status == 200 {
+        return Ok(Ok(response));
+    }
+    match xml_text(&response, "errorCode").and_then(|code| code.parse().ok()) {
+        Some(code) => Ok(Err(UpnpFault { code, description: xml_text(&response, "errorDescription").unwrap_or("no description").to_string() })),
+        None => Err(io::Error::other(format!("{} failed with HTTP status {}", action, status))),
+    }
+}
+
+/// Finds an Internet Gateway Device with an SSDP search and returns the URL of its description.
+async fn discover_gateway() -> io::Result<String> {
+    let socket: UdpSocket = UdpSocket::bind("0.0.0.0:0").await?;
+    let search: String = format!("M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n", SSDP_GROUP, SSDP_PORT, IGD_DEVICE);
+    let mut buffer: [u8; 2048] = [0; 2048];
+    for _ in 0..3 {
+        socket.send_to(search.as_bytes(), (SSDP_GROUP, SSDP_PORT)).await?;
+        let deadline: Instant = Instant::now() + UPNP_TIMEOUT;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+            let (n, _) = received?;
+            if let Some(location) = ssdp_location(&buffer[..n]) {
+                return Ok(location);
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "no UPnP gateway answered the SSDP search"))
+}
+
+/// Returns the local address this host uses to reach `url`, which is what the router forwards to.
+async fn local_ip_towards(url: &HttpUrl) -> io::Result<IpAddr> {
+    let socket: UdpSocket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((url.host.as_str(), url.port)).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Maps the listen port on the router with UPnP IGD and keeps the mapping alive.
+///
+/// The external address is logged whenever the mapping is created or it changes. Routers
+/// that only accept permanent mappings get one without a lease, which is still re-added
+/// periodically in case the router forgets it.
+pub async fn maintain_upnp_mapping(args: Arc<Args>) -> io::Result<()> {
+    let location: String = discover_gateway().await?;
+    let description_url: HttpUrl = parse_http_url(&location).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unsupported UPnP description URL {}", location)))?;
+    let (status, description) = http_exchange(&description_url, "GET", "", "").await?;
+    if status != 200 {
+        return Err(io::Error::other(format!("fetching {} failed with HTTP status {}", location, status)));
+    }
+    let base: HttpUrl = xml_text(&description, "URLBase").and_then(parse_http_url).unwrap_or(description_url);
+    let (service, control) = find_wan_service(&description).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the UPnP gateway has no WANIPConnection or WANPPPConnection service"))?;
+    let control: HttpUrl = join_url(&base, control).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unsupported UPnP control URL {}", control)))?;
+    let internal_ip: IpAddr = local_ip_towards(&control).await?;
+
+    let mut lease: u32 = UPNP_LEASE;
+    let mut mapped: Option<Option<IpAddr>> = None;
+    loop {
+        let mapping: [(&str, String); 8] = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", args.listen_port.to_string()),
+            ("NewProtocol", "TCP".to_string()),
+            ("NewInternalPort", args.listen_port.to_string()),
+            ("NewInternalClient", internal_ip.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", "proxy-stream".to_string()),
+            ("NewLeaseDuration", lease.to_string()),
+        ];
+        match soap_call(&control, service, "AddPortMapping", &mapping).await? {
+            Ok(_) => {}
+            Err(fault) if fault.code == ONLY_PERMANENT_LEASES && lease != 0 => {
+                lease = 0;
+                continue;
+            }
+            Err(fault) => return Err(io::Error::other(format!("the UPnP gateway refused the port mapping: {}", fault))),
+        }
+
+        // The external address is only for the log, so a router that will not tell is no reason to stop.
+        let external: Option<IpAddr> = match soap_call(&control, service, "GetExternalIPAddress", &[]).await {
+            Ok(Ok(response)) => xml_text(&response, "NewExternalIPAddress").and_then(|ip| ip.parse().ok()),
+            _ => None,
+        };
+        if mapped != Some(external) {
+            let external_text: String = external.map_or("an unknown address".to_string(), |ip| ip.to_string());
+            log!(
+                Info,
+                "upnp_mapped",
+                { "gateway" => base.host.as_str(), "external_ip" => external, "port" => args.listen_port, "lease" => u64::from(lease) },
+                "Mapped {}:{} -> port {} via UPnP on {} for {}",
+                external_text,
+                args.listen_port,
+                args.listen_port,
+                base.host,
+                if lease == 0 { "good".to_string() } else { format!("{}s", lease) }
+            );
+            mapped = Some(external);
+        }
+        tokio::time::sleep(Duration::from_secs(u64::from(UPNP_LEASE / 2))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_split_into_host_port_and_path() {
+        assert_eq!(parse_http_url("http://192.168.1.1:5000/rootDesc.xml"), Some(HttpUrl { host: "192.168.1.1".to_string(), port: 5000, path: "/rootDesc.xml".to_string() }));
+        assert_eq!(parse_http_url("http://router"), Some(HttpUrl { host: "router".to_string(), port: 80, path: "/".to_string() }));
+        assert_eq!(parse_http_url("http://[fe80::1]:49152/desc"), Some(HttpUrl { host: "fe80::1".to_string(), port: 49152, path: "/desc".to_string() }));
+        assert_eq!(parse_http_url("https://192.168.1.1/"), None);
+        assert_eq!(parse_http_url("http://192.168.1.1:x/"), None);
+    }
+
+    #[test]
+    fn control_urls_resolve_against_the_description() {
+        let base: HttpUrl = parse_http_url("http://192.168.1.1:5000/igd/rootDesc.xml").unwrap();
+        assert_eq!(join_url(&base, "/ctl/IPConn").unwrap().path, "/ctl/IPConn");
+        assert_eq!(join_url(&base, "ctl/IPConn").unwrap().path, "/igd/ctl/IPConn");
+        assert_eq!(join_url(&base, "http://192.168.1.1:6000/ctl").unwrap().port, 6000);
+    }
+
+    #[test]
+    fn ssdp_responses_give_the_location() {
+        let response: &[u8] = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        assert_eq!(ssdp_location(response).as_deref(), Some("http://192.168.1.1:5000/rootDesc.xml"));
+        assert_eq!(ssdp_location(b"NOTIFY * HTTP/1.1\r\nLOCATION: http://x/\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn the_preferred_wan_service_is_picked() {
+        let description: &str = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType><controlURL>/ppp</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ip</controlURL></service>\
+            </serviceList></device></root>";
+        assert_eq!(find_wan_service(description), Some(("urn:schemas-upnp-org:service:WANIPConnection:1", "/ip")));
+        assert_eq!(find_wan_service("<root><service><serviceType>urn:x</serviceType></service></root>"), None);
+    }
+
+    #[test]
+    fn xml_text_ignores_namespace_prefixes() {
+        let response: &str = "<s:Envelope><s:Body><u:GetExternalIPAddressResponse><NewExternalIPAddress> 203.0.113.7 </NewExternalIPAddress></u:GetExternalIPAddressResponse></s:Body></s:Envelope>";
+        assert_eq!(xml_text(response, "NewExternalIPAddress"), Some("203.0.113.7"));
+        assert_eq!(xml_text("<a:errorCode>725</a:errorCode>", "errorCode"), Some("725"));
+        assert_eq!(xml_text("<x/>", "x"), None);
+    }
+
+    #[test]
+    fn http_responses_are_de_chunked() {
+        let response: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n<root\r\n2;ext\r\n/>\r\n0\r\n\r\n";
+        assert_eq!(parse_http_response(response), Some((200, b"<root/>".to_vec())));
+        assert_eq!(parse_http_response(b"HTTP/1.1 500 Internal Server Error\r\n\r\nfault"), Some((500, b"fault".to_vec())));
+        assert_eq!(parse_http_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nff\r\nshort"), None);
+        assert_eq!(parse_http_response(b"garbage"), None);
+    }
+}
@@ -0,0 +1,23 @@
+//! Small helpers for building binary protocol messages.
+
+/// Returns `n` bytes that are unpredictable enough for handshake randoms in a probe.
+pub fn random_bytes(n: usize) -> Vec<u8> {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let state = RandomState::new();
+    (0..n.div_ceil(8))
+        .flat_map(|i| {
+            let mut hasher = state.build_hasher();
+            hasher.write_usize(i);
+            hasher.finish().to_le_bytes()
+        })
+        .take(n)
+        .collect()
+}
+
+/// Appends `data` to `out` behind a big-endian length prefix of `width` bytes.
+pub fn put_length_prefixed(out: &mut Vec<u8>, width: usize, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes()[4 - width..]);
+    out.extend_from_slice(data);
+}